@@ -7,9 +7,11 @@ from py_near.account import Account
 master_account_id = globals()['env'].env_vars.get("master_account_id", None)
 master_private_key = globals()['env'].env_vars.get("master_private_key", None)
 contract_id = "amm.ai-is-near.near"
+# Fees in the swap request are set in basis points
+FEE_DIVISOR = 10_000
 
 
-async def agent_response(env: Environment, data_id, amount_out):
+async def agent_response(env: Environment, data_id, amount_out, gas):
     # Create an account instance with master account credentials
     acc = Account(master_account_id, master_private_key)
 
@@ -20,7 +22,7 @@ async def agent_response(env: Environment, data_id, amount_out):
     }
 
     # Call the smart contract function 'agent_response' with the prepared arguments
-    tr = await acc.function_call(contract_id, 'agent_response', args, 200000000000000, 0)
+    tr = await acc.function_call(contract_id, 'agent_response', args, gas, 0)
 
     # Add a reply to the agent environment with the transaction hash
     env.add_reply(
@@ -49,13 +51,19 @@ async def main(env: Environment):
         balance_out = int(agent_data.result[1])
         amount_in = int(request.get("amount_in"))
 
-        # AMM formula, calculate amount_out
-        k = balance_in * balance_out
-        new_balance_in = balance_in + amount_in
-        if amount_in > 0 and new_balance_in > 0:
-            new_balance_out = int(k / new_balance_in)
-            amount_out = balance_out - new_balance_out
-            await agent_response(env, request_id, str(amount_out))
+        # The contract takes the creator, referral and agent fees from amount_in before the swap,
+        # each one rounded down, and compares amount_out with the on-chain price of the rest
+        fees = [request.get("creator_fee") or 0, request.get("referral_fee") or 0]
+        if request.get("agent_fee_side", "AmountIn") == "AmountIn":
+            fees.append(request.get("agent_fee") or 0)
+        swap_amount_in = amount_in - sum(amount_in * int(fee) // FEE_DIVISOR for fee in fees)
+
+        # AMM formula in integers, calculate amount_out rounded down like the contract
+        if swap_amount_in > 0 and balance_in + swap_amount_in > 0:
+            amount_out = swap_amount_in * balance_out // (balance_in + swap_amount_in)
+            # Gas for agent_response is set in the contract config
+            gas = int(request.get("agent_response_gas", 200000000000000))
+            await agent_response(env, request_id, str(amount_out), gas)
         else:
             env.add_reply("Illegal amount")
     else:
@@ -0,0 +1,411 @@
+// Harness of the sandbox tests: builds the AMM and the mock token, deploys them to a local
+// near-sandbox and runs a reference agent in the test process. The agent reads the run_agent
+// events from the logs of the swap transaction, prices the swap with get_swap_balances and
+// resumes it with agent_response, the way the NEAR AI agent does on mainnet.
+use anyhow::{anyhow, Context};
+use near_jsonrpc_client::{methods, JsonRpcClient};
+use near_jsonrpc_primitives::types::transactions::{RpcTransactionError, TransactionInfo};
+use near_primitives::views::TxExecutionStatus;
+use near_workspaces::network::Sandbox;
+use near_workspaces::operations::TransactionStatus;
+use near_workspaces::types::{Gas, NearToken};
+use near_workspaces::{Account, AccountId, Contract, Worker};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+pub const FEE_DIVISOR: u128 = 10_000;
+pub const AGENT_NAME: &str = "ai-is-near.near/amm-agent/latest";
+const STORAGE_DEPOSIT: NearToken = NearToken::from_millinear(10);
+// Polls of the swap transaction before the agent gives up waiting for the run_agent events
+const MAX_EVENT_POLLS: usize = 100;
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+// Build a crate of the repo to wasm and return the code. `-C target-cpu=mvp` keeps the wasm
+// features that newer rustc enables by default out of the contracts, the sandbox runtime
+// doesn't support all of them
+pub fn build_wasm(manifest_dir: &Path, package: &str) -> anyhow::Result<Vec<u8>> {
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .current_dir(manifest_dir)
+        .args([
+            "build",
+            "--package",
+            package,
+            "--target",
+            "wasm32-unknown-unknown",
+            "--release",
+        ])
+        .env("RUSTFLAGS", "-C link-arg=-s -C target-cpu=mvp")
+        .status()
+        .with_context(|| format!("failed to run cargo build of {}", package))?;
+    anyhow::ensure!(status.success(), "cargo build of {} failed", package);
+
+    let wasm = manifest_dir
+        .join("target/wasm32-unknown-unknown/release")
+        .join(format!("{}.wasm", package.replace('-', "_")));
+    std::fs::read(&wasm).with_context(|| format!("failed to read {}", wasm.display()))
+}
+
+fn harness_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+pub fn amm_wasm() -> anyhow::Result<Vec<u8>> {
+    build_wasm(&harness_dir().join(".."), "contract")
+}
+
+pub fn mock_ft_wasm() -> anyhow::Result<Vec<u8>> {
+    build_wasm(&harness_dir(), "mock-ft")
+}
+
+// Sandbox with the AMM, two whitelisted mock tokens, the agent account and a user that has
+// `initial_balance` of both tokens
+pub struct TestEnv {
+    pub worker: Worker<Sandbox>,
+    pub amm: Contract,
+    pub token_a: Contract,
+    pub token_b: Contract,
+    pub agent: ReferenceAgent,
+    pub user: Account,
+}
+
+impl TestEnv {
+    pub async fn new(initial_balance: u128) -> anyhow::Result<Self> {
+        let worker = near_workspaces::sandbox().await?;
+        let root = worker.root_account()?;
+
+        let amm_wasm = amm_wasm()?;
+        let ft_wasm = mock_ft_wasm()?;
+
+        let amm = create_account(&root, "amm", 50)
+            .await?
+            .deploy(&amm_wasm)
+            .await?
+            .into_result()?;
+        let mut tokens = Vec::new();
+        for symbol in ["a", "b"] {
+            let token = create_account(&root, &format!("token-{}", symbol), 10)
+                .await?
+                .deploy(&ft_wasm)
+                .await?
+                .into_result()?;
+            token
+                .call("new")
+                .args_json(json!({ "symbol": symbol.to_uppercase(), "decimals": 6 }))
+                .transact()
+                .await?
+                .into_result()?;
+            tokens.push(token);
+        }
+        let token_b = tokens.pop().unwrap();
+        let token_a = tokens.pop().unwrap();
+        let agent_account = create_account(&root, "agent", 10).await?;
+        let user = create_account(&root, "user", 10).await?;
+
+        amm.call("new")
+            .args_json(json!({
+                "agent": AGENT_NAME,
+                "agent_account_id": agent_account.id(),
+            }))
+            .transact()
+            .await?
+            .into_result()?;
+        amm.call("add_whitelisted_tokens")
+            .args_json(json!({ "token_ids": [token_a.id(), token_b.id()] }))
+            .transact()
+            .await?
+            .into_result()?;
+        // pays for the storage of the user's token deposits and pending swaps
+        user.call(amm.id(), "storage_deposit")
+            .args_json(json!({}))
+            .deposit(NearToken::from_near(1))
+            .transact()
+            .await?
+            .into_result()?;
+
+        for token in [&token_a, &token_b] {
+            for account_id in [amm.id(), agent_account.id()] {
+                user.call(token.id(), "storage_deposit")
+                    .args_json(json!({ "account_id": account_id }))
+                    .deposit(STORAGE_DEPOSIT)
+                    .transact()
+                    .await?
+                    .into_result()?;
+            }
+            token
+                .call("mint")
+                .args_json(
+                    json!({ "account_id": user.id(), "amount": initial_balance.to_string() }),
+                )
+                .transact()
+                .await?
+                .into_result()?;
+        }
+
+        let agent = ReferenceAgent::new(&worker, agent_account, amm.id().clone());
+        Ok(TestEnv {
+            worker,
+            amm,
+            token_a,
+            token_b,
+            agent,
+            user,
+        })
+    }
+
+    // Transfer tokens of the account to its AMM deposits
+    pub async fn deposit(
+        &self,
+        account: &Account,
+        token: &Contract,
+        amount: u128,
+    ) -> anyhow::Result<()> {
+        account
+            .call(token.id(), "ft_transfer_call")
+            .args_json(json!({
+                "receiver_id": self.amm.id(),
+                "amount": amount.to_string(),
+                "msg": json!({ "Execute": { "actions": [{ "Deposit": {} }] } }).to_string(),
+            }))
+            .deposit(NearToken::from_yoctonear(1))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+        Ok(())
+    }
+
+    pub async fn ft_balance_of(
+        &self,
+        token: &Contract,
+        account_id: &AccountId,
+    ) -> anyhow::Result<u128> {
+        let balance: String = token
+            .view("ft_balance_of")
+            .args_json(json!({ "account_id": account_id }))
+            .await?
+            .json()?;
+        Ok(balance.parse()?)
+    }
+
+    pub async fn user_deposit(
+        &self,
+        account_id: &AccountId,
+        token: &Contract,
+    ) -> anyhow::Result<u128> {
+        let deposits: Vec<(AccountId, String)> = self
+            .amm
+            .view("get_user_deposits")
+            .args_json(json!({ "account_id": account_id }))
+            .await?
+            .json()?;
+        Ok(deposits
+            .into_iter()
+            .find(|(token_id, _)| token_id == token.id())
+            .map(|(_, amount)| amount.parse())
+            .transpose()?
+            .unwrap_or(0))
+    }
+
+    // Balances of the pool in the swap direction, token_in first
+    pub async fn swap_balances(
+        &self,
+        token_in: &Contract,
+        token_out: &Contract,
+    ) -> anyhow::Result<(u128, u128)> {
+        let (balance_in, balance_out): (String, String) = self
+            .amm
+            .view("get_swap_balances")
+            .args_json(json!({ "token_in": token_in.id(), "token_out": token_out.id() }))
+            .await?
+            .json()?;
+        Ok((balance_in.parse()?, balance_out.parse()?))
+    }
+}
+
+async fn create_account(root: &Account, name: &str, near: u128) -> anyhow::Result<Account> {
+    Ok(root
+        .create_subaccount(name)
+        .initial_balance(NearToken::from_near(near))
+        .transact()
+        .await?
+        .into_result()?)
+}
+
+// Wait for the swap transaction to finish and return its result
+pub async fn wait_for_swap(tx: TransactionStatus) -> anyhow::Result<Option<String>> {
+    loop {
+        if let std::task::Poll::Ready(result) = tx.status().await? {
+            return Ok(result.into_result()?.json()?);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Swap request of a run_agent event
+#[derive(Debug, Clone)]
+pub struct AgentRequest {
+    // data_id of the yield, sent back with agent_response
+    pub data_id: [u8; 32],
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: u128,
+    pub min_amount_out: u128,
+    // Creator and referral fee of the swap in basis points, from the env vars of the run
+    pub fee: u128,
+    pub agent_response_gas: Gas,
+}
+
+#[derive(Deserialize)]
+struct SwapMessage {
+    token_in: AccountId,
+    token_out: AccountId,
+    amount_in: String,
+    min_amount_out: String,
+    agent_response_gas: Gas,
+}
+
+impl AgentRequest {
+    fn from_event(data: &Value) -> anyhow::Result<Self> {
+        let data_id: Vec<u8> = serde_json::from_value(data["request_id"].clone())?;
+        let message: SwapMessage =
+            serde_json::from_str(data["message"].as_str().ok_or(anyhow!("no message"))?)?;
+        let env_vars: Value =
+            serde_json::from_str(data["env_vars"].as_str().ok_or(anyhow!("no env_vars"))?)?;
+
+        Ok(AgentRequest {
+            data_id: data_id
+                .try_into()
+                .map_err(|_| anyhow!("wrong request_id length"))?,
+            token_in: message.token_in,
+            token_out: message.token_out,
+            amount_in: message.amount_in.parse()?,
+            min_amount_out: message.min_amount_out.parse()?,
+            fee: env_vars["fee"]
+                .as_str()
+                .ok_or(anyhow!("no fee env var"))?
+                .parse()?,
+            agent_response_gas: message.agent_response_gas,
+        })
+    }
+}
+
+// Requests of the run_agent events in the logs
+pub fn parse_run_agent_events<'a>(
+    logs: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<Vec<AgentRequest>> {
+    let mut requests = Vec::new();
+    for log in logs {
+        let Some(event) = log.strip_prefix("EVENT_JSON:") else {
+            continue;
+        };
+        let event: Value = serde_json::from_str(event)?;
+        if event["standard"] == "nearai" && event["event"] == "run_agent" {
+            for data in event["data"].as_array().into_iter().flatten() {
+                requests.push(AgentRequest::from_event(data)?);
+            }
+        }
+    }
+    Ok(requests)
+}
+
+// Agent that runs in the test process and signs agent_response with the agent account
+pub struct ReferenceAgent {
+    pub account: Account,
+    amm_id: AccountId,
+    rpc: JsonRpcClient,
+}
+
+impl ReferenceAgent {
+    pub fn new(worker: &Worker<Sandbox>, account: Account, amm_id: AccountId) -> Self {
+        ReferenceAgent {
+            account,
+            amm_id,
+            rpc: JsonRpcClient::connect(worker.rpc_addr()),
+        }
+    }
+
+    // Wait for the swap transaction to emit `count` run_agent events. The transaction isn't
+    // finished while the swaps are yielded, so the partial outcome is read with the `tx` RPC
+    // method, near-workspaces only returns finished transactions
+    pub async fn wait_for_requests(
+        &self,
+        tx: &TransactionStatus,
+        count: usize,
+    ) -> anyhow::Result<Vec<AgentRequest>> {
+        let request = methods::tx::RpcTransactionStatusRequest {
+            transaction_info: TransactionInfo::TransactionId {
+                tx_hash: tx
+                    .hash()
+                    .to_string()
+                    .parse()
+                    .map_err(|err| anyhow!("{:?}", err))?,
+                sender_account_id: tx.sender_id().clone(),
+            },
+            wait_until: TxExecutionStatus::Included,
+        };
+
+        for _ in 0..MAX_EVENT_POLLS {
+            match self.rpc.call(&request).await {
+                Ok(response) => {
+                    if let Some(outcome) = response.final_execution_outcome {
+                        let outcome = outcome.into_outcome();
+                        let logs = std::iter::once(&outcome.transaction_outcome)
+                            .chain(outcome.receipts_outcome.iter())
+                            .flat_map(|receipt| receipt.outcome.logs.iter().map(String::as_str));
+                        let requests = parse_run_agent_events(logs)?;
+                        if requests.len() >= count {
+                            return Ok(requests);
+                        }
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.handler_error(),
+                        Some(RpcTransactionError::UnknownTransaction { .. })
+                    ) => {}
+                Err(err) => return Err(err.into()),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(anyhow!("no {} run_agent events in {}", count, tx.hash()))
+    }
+
+    // Constant product output of the swap after the fees, at the current pool balances
+    pub async fn quote(&self, request: &AgentRequest) -> anyhow::Result<u128> {
+        let (balance_in, balance_out): (String, String) = self
+            .account
+            .view(&self.amm_id, "get_swap_balances")
+            .args_json(json!({ "token_in": request.token_in, "token_out": request.token_out }))
+            .await?
+            .json()?;
+        let (balance_in, balance_out): (u128, u128) = (balance_in.parse()?, balance_out.parse()?);
+
+        let amount_in = request.amount_in * (FEE_DIVISOR - request.fee) / FEE_DIVISOR;
+        Ok(amount_in * balance_out / (balance_in + amount_in))
+    }
+
+    // Resume the swap with amount_out
+    pub async fn respond(&self, request: &AgentRequest, amount_out: u128) -> anyhow::Result<()> {
+        self.account
+            .call(&self.amm_id, "agent_response")
+            .args_json(json!({
+                "data_id": request.data_id,
+                "amount_out": amount_out.to_string(),
+            }))
+            .gas(request.agent_response_gas)
+            .transact()
+            .await?
+            .into_result()?;
+        Ok(())
+    }
+
+    // Quote and resume the swap, returns the amount_out sent
+    pub async fn resolve(&self, request: &AgentRequest) -> anyhow::Result<u128> {
+        let amount_out = self.quote(request).await?;
+        self.respond(request, amount_out).await?;
+        Ok(amount_out)
+    }
+}
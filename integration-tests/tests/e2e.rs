@@ -0,0 +1,155 @@
+// End-to-end swaps through the yield-resume flow on near-sandbox.
+// Run with `cargo test` in integration-tests, see README.md
+use integration_tests::{wait_for_swap, TestEnv};
+use near_workspaces::types::NearToken;
+use serde_json::json;
+
+const INITIAL_BALANCE: u128 = 10_000_000;
+const POOL_RESERVE: u128 = 1_000_000;
+// Blocks after which a yielded swap times out
+const YIELD_TIMEOUT_BLOCKS: u64 = 200;
+
+async fn env_with_pool() -> anyhow::Result<TestEnv> {
+    let env = TestEnv::new(INITIAL_BALANCE).await?;
+    env.deposit(&env.user, &env.token_a, INITIAL_BALANCE)
+        .await?;
+    env.deposit(&env.user, &env.token_b, INITIAL_BALANCE)
+        .await?;
+
+    env.user
+        .call(env.amm.id(), "create_pool")
+        .args_json(json!({
+            "token_a": env.token_a.id(),
+            "token_a_amount": POOL_RESERVE.to_string(),
+            "token_b": env.token_b.id(),
+            "token_b_amount": POOL_RESERVE.to_string(),
+            "creator_fee": 30,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(env)
+}
+
+#[tokio::test]
+async fn test_agent_resolves_swap() -> anyhow::Result<()> {
+    let env = env_with_pool().await?;
+    let amount_in = 10_000;
+
+    let swap = env
+        .user
+        .call(env.amm.id(), "swap")
+        .args_json(json!({
+            "token_in": env.token_a.id(),
+            "token_out": env.token_b.id(),
+            "amount_in": amount_in.to_string(),
+            "min_amount_out": "1",
+        }))
+        .max_gas()
+        .transact_async()
+        .await?;
+
+    let requests = env.agent.wait_for_requests(&swap, 1).await?;
+    let request = &requests[0];
+    assert_eq!(request.token_in, *env.token_a.id());
+    assert_eq!(request.amount_in, amount_in);
+    assert_eq!(request.fee, 30);
+    let amount_out = env.agent.resolve(request).await?;
+
+    let result = wait_for_swap(swap).await?;
+    assert_eq!(result, Some(amount_out.to_string()));
+
+    // amount_out is sent to the user's wallet, amount_in net of the creator fee goes to the pool
+    let balance_b = env.ft_balance_of(&env.token_b, env.user.id()).await?;
+    assert_eq!(balance_b, amount_out);
+    assert_eq!(
+        env.user_deposit(env.user.id(), &env.token_a).await?,
+        INITIAL_BALANCE - POOL_RESERVE - amount_in + 30
+    );
+    let (balance_in, balance_out) = env.swap_balances(&env.token_a, &env.token_b).await?;
+    assert_eq!(balance_in, POOL_RESERVE + amount_in - 30);
+    assert_eq!(balance_out, POOL_RESERVE - amount_out);
+
+    let stats: serde_json::Value = env
+        .amm
+        .view("get_agent_stats")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(stats["resolved"], 1);
+    assert_eq!(stats["pending"], 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_resolves_swaps_of_one_transfer() -> anyhow::Result<()> {
+    let env = env_with_pool().await?;
+
+    // two swaps of one ft_transfer_call, each has its own yield
+    let swap = env
+        .user
+        .call(env.token_b.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": env.amm.id(),
+            "amount": "20000",
+            "msg": json!({ "Execute": { "actions": [
+                { "Swap": { "token_out": env.token_a.id(), "min_amount_out": "1", "amount": "10000" } },
+                { "Swap": { "token_out": env.token_a.id(), "min_amount_out": "1" } },
+            ] } }).to_string(),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact_async()
+        .await?;
+
+    let requests = env.agent.wait_for_requests(&swap, 2).await?;
+    let mut total_out = 0;
+    for request in &requests {
+        assert_eq!(request.token_out, *env.token_a.id());
+        total_out += env.agent.resolve(request).await?;
+    }
+    wait_for_swap(swap).await?;
+
+    let balance_a = env.ft_balance_of(&env.token_a, env.user.id()).await?;
+    assert_eq!(balance_a, total_out);
+    let (_, balance_out) = env.swap_balances(&env.token_b, &env.token_a).await?;
+    assert_eq!(balance_out, POOL_RESERVE - total_out);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_refunded_after_yield_timeout() -> anyhow::Result<()> {
+    let env = env_with_pool().await?;
+    let deposit_before = env.user_deposit(env.user.id(), &env.token_a).await?;
+
+    let swap = env
+        .user
+        .call(env.amm.id(), "swap")
+        .args_json(json!({
+            "token_in": env.token_a.id(),
+            "token_out": env.token_b.id(),
+            "amount_in": "10000",
+            "min_amount_out": "1",
+        }))
+        .max_gas()
+        .transact_async()
+        .await?;
+    env.agent.wait_for_requests(&swap, 1).await?;
+
+    // the agent doesn't respond, the swap times out and amount_in goes back to deposits
+    env.worker.fast_forward(YIELD_TIMEOUT_BLOCKS + 10).await?;
+    assert_eq!(wait_for_swap(swap).await?, None);
+    assert_eq!(
+        env.user_deposit(env.user.id(), &env.token_a).await?,
+        deposit_before
+    );
+
+    let stats: serde_json::Value = env
+        .amm
+        .view("get_agent_stats")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(stats["timed_out"], 1);
+    Ok(())
+}
@@ -1,28 +1,77 @@
 use crate::*;
+use std::collections::BTreeMap;
+
+// Swap waiting for the agent response, keyed by request_id
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct PendingSwap {
+    pub data_id: CryptoHash,
+    pub batch_id: u64,
+    pub request: SwapRequest,
+    // Output of token_out reserved in the pool until the agent responds
+    pub reserved_out: Balance,
+    // Block height of the request, used for the agent latency stats
+    pub requested_at: u64,
+    // Storage deposit of the sender locked until the swap is settled
+    pub storage_cost: Balance,
+}
 
 impl Contract {
-    // Run agent to interrupt the swap transaction and request the agent to provide the output amount
-    pub fn run_agent_market_maker(
-        &mut self,
-        sender_id: AccountId,
-        token_in: AccountId,
-        token_out: AccountId,
-        amount_in: Balance,
-        min_amount_out: Balance,
-    ) {
+    // Run agent to interrupt the swap transaction and request the agent to provide the output
+    // amount. A swap that joins the open batch of its pool is queued without a yield or event,
+    // the agent resolves it with the batch
+    pub fn run_agent_market_maker(&mut self, request: SwapRequest) -> SwapResult {
+        let initial_storage_usage = env::storage_usage();
+        // amount_in is held in the pending swap until the agent responds
+        self.internal_withdraw_deposit(&request.sender_id, &request.token_in, request.amount_in.0);
+        self.internal_add_user_pending_swap(&request.sender_id);
+        let reserved_out = self.internal_reserve_output(&request);
+
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let pool_key = get_pool_key(&request.token_in, &request.token_out);
+        if let Some(mut batch) = self.internal_open_batch(&pool_key) {
+            // the agent is run once for the batch
+            self.internal_refund_agent_payment(&request);
+            batch.request_ids.push(request_id);
+            self.swap_batches.insert(&batch.batch_id, &batch);
+            self.internal_add_pending_swap(
+                request_id,
+                PendingSwap {
+                    data_id: batch.data_id,
+                    batch_id: batch.batch_id,
+                    request,
+                    reserved_out,
+                    requested_at: env::block_height(),
+                    storage_cost: 0,
+                },
+                initial_storage_usage,
+            );
+            return SwapResult::Queued;
+        }
+
+        // the agent runner is paid when the run is requested, whatever the agent responds
+        let agent_payment = self.internal_pay_agent_runner(&request);
+
         let swap_request_data = json!({
-            "sender_id": sender_id.clone(),
-            "token_in": token_in.clone(),
-            "token_out": token_out.clone(),
-            "amount_in": U128::from(amount_in),
-            "min_amount_out": U128::from(min_amount_out)
+            "request_id": request_id,
+            "sender_id": request.sender_id,
+            "receiver_id": request.receiver_id.as_ref().unwrap_or(&request.sender_id),
+            "token_in": request.token_in,
+            "token_out": request.token_out,
+            "amount_in": request.amount_in,
+            "min_amount_out": request.min_amount_out,
+            "referral_id": request.referral_id
         });
 
         // Create a promise to resume the swap transaction after the agent responds
         let promise_idx = env::promise_yield_create(
             "on_agent_market_maker_response",
-            &swap_request_data.to_string().into_bytes(),
-            MIN_RESPONSE_GAS,
+            json!({ "request_id": request_id }).to_string().into_bytes(),
+            self.config
+                .response_gas
+                .saturating_add(self.send_tokens_gas(&request.msg)),
             GasWeight::default(),
             DATA_ID_REGISTER,
         );
@@ -33,11 +82,298 @@ impl Contract {
             .try_into()
             .expect("Wrong register length");
 
+        // Keep the request until the callback, the swap opens a batch that others can join
+        let token_in = request.token_in.clone();
+        let token_out = request.token_out.clone();
+        let referral_id = request.referral_id.clone();
+        let env_vars = self.agent_env_vars(&request);
+        let batch = SwapBatch {
+            batch_id: request_id,
+            pool_id: pool_key.clone(),
+            opened_at: env::block_height(),
+            data_id,
+            request_ids: vec![request_id],
+        };
+        self.internal_add_batch(&batch);
+        self.pending_data_ids.insert(&data_id, &request_id);
+        self.internal_add_pending_swap(
+            request_id,
+            PendingSwap {
+                data_id,
+                batch_id: request_id,
+                request,
+                reserved_out,
+                requested_at: env::block_height(),
+                storage_cost: 0,
+            },
+            initial_storage_usage,
+        );
+
         // emit the agent event with the swap request data
-        events::emit::run_agent(&self.agent, &swap_request_data.to_string(), Some(data_id));
+        let pool = self.pools.get(&pool_key);
+        let mut message = swap_request_data;
+        message["pool_id"] = json!(pool_key);
+        message["batch_id"] = json!(request_id);
+        // swaps of the pool join the batch until this block, get_swap_batch lists them
+        message["batch_ends_at"] = json!(batch.opened_at + self.batch_window_blocks);
+        message["creator_fee"] = json!(pool.as_ref().map(|pool| pool.creator_fee));
+        message["pricing_mode"] = json!(pool.as_ref().map(|pool| pool.pricing_mode));
+        // output of token_out reserved for all pending swaps of the pool, including this one
+        message["reserved_out"] = json!(pool.map(|pool| U128(pool.reserved(&token_out))));
+        message["tokens_metadata"] = self.tokens_metadata_json(&token_in, &token_out);
+        message["agent_fee"] = json!(self.config.agent_fee);
+        message["agent_fee_side"] = json!(self.config.agent_fee_side);
+        message["agent_response_gas"] = json!(self.config.agent_response_gas);
+        message["referral_fee"] = json!(referral_id.as_ref().map(|_| self.referral_fee));
+        events::emit::run_agent(
+            &self.agent,
+            &message.to_string(),
+            Some(data_id),
+            &referral_id,
+            &events::emit::AgentRun {
+                max_iterations: self.config.agent_max_iterations,
+                thread_id: self.config.agent_thread_id.clone(),
+                env_vars: Some(json!(env_vars).to_string()),
+                amount: agent_payment,
+            },
+        );
 
         // Return the promise index to the caller
-        env::promise_return(promise_idx);
+        SwapResult::Pending(promise_idx)
+    }
+
+    // Send the NEAR attached to the swap to the NEAR AI runner, returns the amount paid
+    fn internal_pay_agent_runner(&self, request: &SwapRequest) -> Option<Balance> {
+        let amount = request
+            .agent_payment
+            .map(|amount| amount.0)
+            .filter(|amount| *amount > 0)?;
+        let Some(runner_id) = self.config.agent_runner_id.clone() else {
+            self.internal_refund_agent_payment(request);
+            return None;
+        };
+        Promise::new(runner_id)
+            .transfer(NearToken::from_yoctonear(amount))
+            .detach();
+        Some(amount)
+    }
+
+    // The NEAR attached to a swap that doesn't run the agent goes back to the sender
+    pub fn internal_refund_agent_payment(&self, request: &SwapRequest) {
+        if let Some(U128(amount)) = request.agent_payment.filter(|amount| amount.0 > 0) {
+            Promise::new(request.sender_id.clone())
+                .transfer(NearToken::from_yoctonear(amount))
+                .detach();
+        }
+    }
+
+    // Keep the request until it's resolved, the sender pays for its storage from its storage
+    // deposit
+    fn internal_add_pending_swap(
+        &mut self,
+        request_id: u64,
+        mut pending_swap: PendingSwap,
+        initial_storage_usage: u64,
+    ) {
+        self.pending_swaps.insert(&request_id, &pending_swap);
+        self.internal_record_agent_request();
+
+        pending_swap.storage_cost = storage::storage_cost(initial_storage_usage);
+        self.internal_lock_storage(&pending_swap.request.sender_id, pending_swap.storage_cost);
+        self.pending_swaps.insert(&request_id, &pending_swap);
+    }
+
+    // Env vars of the agent run: the ones from the config and the context of the request,
+    // the total fee in basis points and the output at the on-chain price as a quote hint
+    fn agent_env_vars(&self, request: &SwapRequest) -> BTreeMap<String, String> {
+        let pool_key = get_pool_key(&request.token_in, &request.token_out);
+        let pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
+        let referral_fee = request
+            .referral_id
+            .as_ref()
+            .map(|_| self.referral_fee)
+            .unwrap_or(0);
+        let (agent_fee_in, _) = self.agent_fees(request.amount_in.0, 0);
+
+        let mut env_vars = self.config.agent_env_vars.clone();
+        env_vars.insert("pool_id".to_string(), pool_key);
+        env_vars.insert(
+            "fee".to_string(),
+            (pool.creator_fee + referral_fee).to_string(),
+        );
+        env_vars.insert(
+            "quote_hint".to_string(),
+            self.on_chain_amount_out(request, agent_fee_in)
+                .unwrap_or_panic()
+                .to_string(),
+        );
+        env_vars
+    }
+
+    pub fn assert_agent(&self) {
+        require(
+            env::predecessor_account_id() == self.agent_account_id,
+            ContractError::IllegalAgent,
+        );
+    }
+
+    // Resume the yielded swap transaction with the amount_out from agent
+    pub fn internal_resume_swap(&mut self, data_id: &CryptoHash, amount_out: U128) -> bool {
+        let resumed = env::promise_yield_resume(data_id, serde_json::to_vec(&amount_out).unwrap());
+        if !resumed {
+            log!("Unable to resume promise");
+        } else if let Some(pending_swap) = self
+            .pending_data_ids
+            .get(data_id)
+            .and_then(|request_id| self.pending_swaps.get(&request_id))
+        {
+            self.internal_record_agent_response(pending_swap.requested_at);
+        }
+        resumed
+    }
+
+    fn internal_remove_pending_swap(&mut self, request_id: u64) -> PendingSwap {
+        let pending_swap = self
+            .pending_swaps
+            .remove(&request_id)
+            .or_panic(ContractError::SwapRequestNotFound);
+        // the yield of a batch belongs to its first swap
+        if pending_swap.batch_id == request_id {
+            self.pending_data_ids.remove(&pending_swap.data_id);
+        }
+        self.internal_remove_from_batch(request_id, pending_swap.batch_id);
+        self.internal_release_output(&pending_swap);
+        self.internal_remove_user_pending_swap(&pending_swap.request.sender_id);
+        self.internal_unlock_storage(&pending_swap.request.sender_id, pending_swap.storage_cost);
+        pending_swap
+    }
+
+    // Settle the pending swap with the amount_out of the agent. The deviation of the agent
+    // from the on-chain price is checked against the reserves before the swap
+    pub fn internal_settle_agent_swap(
+        &mut self,
+        request_id: u64,
+        amount_out: Balance,
+    ) -> Option<U128> {
+        let request = self.internal_remove_pending_swap(request_id).request;
+        let agent_fees = self.agent_fees(request.amount_in.0, amount_out);
+        let deviation = self.internal_check_agent_quote(&request, amount_out);
+        let result = self.internal_settle_swap(request.clone(), amount_out, agent_fees);
+        match (result, deviation) {
+            // every response that gets amount_in refunded is rejected
+            (None, _) => self.internal_record_agent_rejection(),
+            (Some(_), Some(deviation)) => self.internal_slash_agent(&request, deviation),
+            (Some(_), None) => (),
+        }
+        result
+    }
+
+    // Pay the fees, update the pool and send amount_out to the receiver. amount_in goes back
+    // to deposits if amount_out is below min_amount_out or the pool can't take the swap, so a
+    // swap settled after its yield never panics. agent_fees are the parts of amount_in and
+    // amount_out paid to the agent
+    pub fn internal_settle_swap(
+        &mut self,
+        request: SwapRequest,
+        amount_out: Balance,
+        agent_fees: (Balance, Balance),
+    ) -> Option<U128> {
+        let SwapRequest {
+            sender_id,
+            token_in,
+            token_out,
+            amount_in,
+            min_amount_out,
+            receiver_id,
+            msg,
+            referral_id,
+            zap,
+            ..
+        } = request;
+
+        let (agent_fee_in, agent_fee_out) = agent_fees;
+        if amount_out - agent_fee_out < min_amount_out.0 {
+            log!(
+                "Output amount {} is less than minimum {}",
+                amount_out - agent_fee_out,
+                min_amount_out.0
+            );
+            self.internal_deposit(&sender_id, &token_in, amount_in.0);
+            return None;
+        }
+        let swap_amount_in =
+            self.swap_amount_after_fees(&token_in, &token_out, amount_in.0, &referral_id)
+                - agent_fee_in;
+        if let Err(error) = self.check_swap(&token_in, &token_out, swap_amount_in, amount_out) {
+            log!("{}, amount_in is returned to deposits", error);
+            self.internal_deposit(&sender_id, &token_in, amount_in.0);
+            return None;
+        }
+
+        // pay the pool creator, the referrer and the agent, update pool balances
+        let amount_in =
+            self.internal_take_swap_fees(&token_in, &token_out, amount_in.0, &referral_id);
+        let amount_out =
+            self.internal_swap(&token_in, &token_out, amount_in - agent_fee_in, amount_out);
+        let agent_account_id = self.agent_account_id.clone();
+        if agent_fee_in > 0 {
+            self.internal_deposit(&agent_account_id, &token_in, agent_fee_in);
+        }
+        if agent_fee_out > 0 {
+            self.internal_deposit(&agent_account_id, &token_out, agent_fee_out);
+        }
+
+        // the output of the swap leg of a zap stays in deposits for the rest of the zap
+        let amount_out = amount_out - agent_fee_out;
+        if let Some(zap) = zap {
+            self.internal_deposit(&sender_id, &token_out, amount_out);
+            let result =
+                self.internal_finish_zap(&sender_id, &token_in, &token_out, amount_out, zap);
+            return Some(U128(result));
+        }
+
+        // send the output token to the receiver
+        let receiver_id = receiver_id.unwrap_or_else(|| sender_id.clone());
+        self.internal_send_tokens(&sender_id, receiver_id, token_out, amount_out, msg);
+
+        Some(U128(amount_out))
+    }
+
+    // Reserve min_amount_out of the swap, or the output at the current on-chain price if
+    // reserve_expected_output is set and it's larger, so the liquidity isn't taken meanwhile
+    fn internal_reserve_output(&mut self, request: &SwapRequest) -> Balance {
+        let pool_key = get_pool_key(&request.token_in, &request.token_out);
+        let mut pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
+
+        let mut amount = request.min_amount_out.0;
+        if self.reserve_expected_output {
+            amount = amount.max(pool.get_amount_out(&request.token_in, request.amount_in.0));
+        }
+        let reserved = math::add(pool.reserved(&request.token_out), amount).unwrap_or_panic();
+        pool.set_reserved(&request.token_out, reserved);
+        pool.assert_reserved_covered();
+
+        self.pools.insert(&pool_key, &pool);
+        amount
+    }
+
+    fn internal_release_output(&mut self, pending_swap: &PendingSwap) {
+        let request = &pending_swap.request;
+        let pool_key = get_pool_key(&request.token_in, &request.token_out);
+        if let Some(mut pool) = self.pools.get(&pool_key) {
+            let reserved = pool.reserved(&request.token_out);
+            pool.set_reserved(
+                &request.token_out,
+                reserved.saturating_sub(pending_swap.reserved_out),
+            );
+            self.pools.insert(&pool_key, &pool);
+        }
     }
 }
 
@@ -47,15 +383,11 @@ impl Contract {
     pub fn agent_response(&mut self, data_id: CryptoHash, amount_out: U128) {
         log!("Agent resolved the swap. Amount_out: {}", amount_out.0);
 
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.agent_account_id,
-            "Illegal agent account_id"
-        );
+        self.assert_agent();
 
         // resume the initial swap transaction with the amount_out from agent
-        if !env::promise_yield_resume(&data_id, &serde_json::to_vec(&amount_out).unwrap()) {
-            env::panic_str("Unable to resume promise")
+        if !self.internal_resume_swap(&data_id, amount_out) {
+            ContractError::UnableToResume.panic()
         }
     }
 
@@ -63,40 +395,50 @@ impl Contract {
     // Callback function to handle the agent response
     pub fn on_agent_market_maker_response(
         &mut self,
-        sender_id: AccountId,
-        token_in: AccountId,
-        token_out: AccountId,
-        amount_in: U128,
-        min_amount_out: U128,
+        request_id: u64,
         #[callback_result] amount_out: Result<U128, PromiseError>,
-    ) -> Option<Balance> {
-        if let Ok(response) = amount_out.as_ref() {
-            let amount_out = response.0;
+    ) -> Option<U128> {
+        let result = match amount_out {
+            Ok(U128(amount_out)) => self.internal_settle_agent_swap(request_id, amount_out),
+            Err(_) => {
+                self.internal_record_agent_timeout();
+                let request = self.internal_remove_pending_swap(request_id).request;
+                self.internal_settle_timed_out_swap(request)
+            }
+        };
 
-            assert!(
-                amount_out >= min_amount_out.0,
-                "Output amount {} is less than minimum {}",
-                amount_out,
-                min_amount_out.0
-            );
+        // the batch closes with its yield, the queued swaps the agent didn't resolve are
+        // refunded as there's no gas to settle them here
+        if let Some(batch) = self.internal_close_batch(request_id) {
+            for request_id in batch.request_ids {
+                self.internal_record_agent_timeout();
+                let request = self.internal_remove_pending_swap(request_id).request;
+                log!("Agent didn't resolve swap {} of the batch", request_id);
+                self.internal_deposit(&request.sender_id, &request.token_in, request.amount_in.0);
+            }
+        }
+        result
+    }
+}
+
+impl Contract {
+    // Settle the swap the agent didn't resolve in time at the on-chain price, or refund it
+    fn internal_settle_timed_out_swap(&mut self, request: SwapRequest) -> Option<U128> {
+        let pricing_mode = self
+            .pools
+            .get(&get_pool_key(&request.token_in, &request.token_out))
+            .map(|pool| pool.pricing_mode);
 
-            // update pool balances
-            let amount_out = self.internal_swap(&token_in, &token_out, amount_in.0, amount_out);
-
-            // send the output token to the sender
-            ext_ft::ext(token_out.clone())
-                .with_static_gas(GAS_FOR_FT_TRANSFER)
-                .with_attached_deposit(NearToken::from_yoctonear(1))
-                .ft_transfer(
-                    sender_id,
-                    U128(amount_out),
-                    Some("Swap completed".to_string()),
-                );
-
-            Some(amount_out)
-        } else {
-            log!("Response error");
-            None
+        match pricing_mode {
+            Some(PricingMode::AgentWithFallback) => {
+                log!("Agent didn't respond, the swap is settled at the on-chain price");
+                self.internal_settle_swap_on_chain(request)
+            }
+            _ => {
+                log!("Response error");
+                self.internal_deposit(&request.sender_id, &request.token_in, request.amount_in.0);
+                None
+            }
         }
     }
 }
@@ -0,0 +1,176 @@
+use crate::*;
+
+// Swaps of one pool requested within one batch window. The first swap opens the batch with
+// the only yield and run_agent event of the batch, the swaps that join it are queued until the
+// agent resolves the batch in one transaction. The batch_id is the request_id of the first swap
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapBatch {
+    pub batch_id: u64,
+    pub pool_id: String,
+    pub opened_at: u64,
+    // Yield of the first swap, resumed by the agent to close the batch
+    pub data_id: CryptoHash,
+    // Swaps of the batch that are still pending, the first swap included
+    pub request_ids: Vec<u64>,
+}
+
+// Uniform price for a batch: `token_a_amount` of the pool token_a trades for
+// `token_b_amount` of the pool token_b, in both directions
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClearingPrice {
+    pub token_a_amount: U128,
+    pub token_b_amount: U128,
+}
+
+impl ClearingPrice {
+    pub fn amount_out(&self, pool: &Pool, token_in: &AccountId, amount_in: Balance) -> Balance {
+        let (price_in, price_out) = if token_in == &pool.token_a {
+            (self.token_a_amount.0, self.token_b_amount.0)
+        } else {
+            (self.token_b_amount.0, self.token_a_amount.0)
+        };
+        require(price_in > 0, ContractError::InvalidClearingPrice);
+
+        math::mul_div_floor(amount_in, price_out, price_in).unwrap_or_panic()
+    }
+}
+
+impl Contract {
+    // Open batch of the pool that new swaps can join, until its window has passed
+    pub fn internal_open_batch(&self, pool_key: &String) -> Option<SwapBatch> {
+        self.open_swap_batches
+            .get(pool_key)
+            .and_then(|batch_id| self.swap_batches.get(&batch_id))
+            .filter(|batch| env::block_height() < batch.opened_at + self.batch_window_blocks)
+    }
+
+    pub fn internal_add_batch(&mut self, batch: &SwapBatch) {
+        self.swap_batches.insert(&batch.batch_id, batch);
+        self.open_swap_batches
+            .insert(&batch.pool_id, &batch.batch_id);
+    }
+
+    pub fn internal_remove_from_batch(&mut self, request_id: u64, batch_id: u64) {
+        if let Some(mut batch) = self.swap_batches.get(&batch_id) {
+            batch.request_ids.retain(|id| *id != request_id);
+            self.swap_batches.insert(&batch_id, &batch);
+        }
+    }
+
+    // Remove the batch once the yield of its first swap is resolved
+    pub fn internal_close_batch(&mut self, batch_id: u64) -> Option<SwapBatch> {
+        let batch = self.swap_batches.remove(&batch_id)?;
+        if self.open_swap_batches.get(&batch.pool_id) == Some(batch_id) {
+            self.open_swap_batches.remove(&batch.pool_id);
+        }
+        Some(batch)
+    }
+
+    // Resolve a pending swap with amount_out: a queued swap settles right away, the first swap
+    // of a batch resumes its yield. Returns false if the swap isn't pending or can't be resumed
+    fn internal_resolve_pending_swap(&mut self, request_id: u64, amount_out: U128) -> bool {
+        match self.pending_swaps.get(&request_id) {
+            Some(pending_swap) if pending_swap.batch_id == request_id => {
+                self.internal_resume_swap(&pending_swap.data_id, amount_out)
+            }
+            Some(pending_swap) => {
+                self.internal_record_agent_response(pending_swap.requested_at);
+                self.internal_settle_agent_swap(request_id, amount_out.0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Output of the swap at the clearing price, for the part of amount_in that reaches the
+    // pool after fees like the output the agent quotes for single swaps
+    fn clearing_amount_out(&self, price: &ClearingPrice, pool: &Pool, request_id: u64) -> U128 {
+        let request = self
+            .pending_swaps
+            .get(&request_id)
+            .or_panic(ContractError::SwapRequestNotFound)
+            .request;
+        let (agent_fee_in, _) = self.agent_fees(request.amount_in.0, 0);
+        let amount_in = self.swap_amount_after_fees(
+            &request.token_in,
+            &request.token_out,
+            request.amount_in.0,
+            &request.referral_id,
+        ) - agent_fee_in;
+
+        U128(price.amount_out(pool, &request.token_in, amount_in))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn set_batch_window(&mut self, batch_window_blocks: u64) {
+        self.batch_window_blocks = batch_window_blocks;
+    }
+
+    pub fn get_swap_batch(&self, batch_id: u64) -> Option<SwapBatch> {
+        self.swap_batches.get(&batch_id)
+    }
+
+    // Agent resolves several swaps with individual output amounts in one call, queued swaps
+    // settle right away and the first swaps of batches are resumed.
+    // Returns the request_ids of the swaps that couldn't be resolved
+    pub fn agent_response_batch(&mut self, responses: Vec<(u64, U128)>) -> Vec<u64> {
+        self.assert_agent();
+
+        responses
+            .into_iter()
+            .filter(|(request_id, amount_out)| {
+                !self.internal_resolve_pending_swap(*request_id, *amount_out)
+            })
+            .map(|(request_id, _)| request_id)
+            .collect()
+    }
+
+    // Agent resolves all swaps of batches of one pool at a uniform clearing price, a batch is
+    // identified by the data_id of its run_agent event.
+    // Returns the data_ids of the batches that aren't pending or couldn't be resumed
+    pub fn agent_response_batch_uniform(
+        &mut self,
+        data_ids: Vec<CryptoHash>,
+        price: ClearingPrice,
+    ) -> Vec<CryptoHash> {
+        self.assert_agent();
+
+        let mut pool_key: Option<String> = None;
+        let mut failed = vec![];
+        for data_id in data_ids {
+            let Some(batch) = self
+                .pending_data_ids
+                .get(&data_id)
+                .and_then(|batch_id| self.swap_batches.get(&batch_id))
+            else {
+                failed.push(data_id);
+                continue;
+            };
+            require(
+                pool_key.get_or_insert_with(|| batch.pool_id.clone()) == &batch.pool_id,
+                ContractError::BatchPoolMismatch,
+            );
+
+            let pool = self
+                .pools
+                .get(&batch.pool_id)
+                .or_panic(ContractError::PoolNotFound);
+            // queued swaps first, the first swap closes the batch when its yield resumes
+            let mut resumed = true;
+            for request_id in batch.request_ids.iter().rev() {
+                let amount_out = self.clearing_amount_out(&price, &pool, *request_id);
+                resumed = self.internal_resolve_pending_swap(*request_id, amount_out);
+            }
+            if !resumed {
+                failed.push(data_id);
+            }
+        }
+        failed
+    }
+}
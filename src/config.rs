@@ -0,0 +1,209 @@
+use crate::*;
+use std::collections::BTreeMap;
+
+// Max gas of one function call
+const MAX_GAS: Gas = Gas::from_tgas(300);
+const DEFAULT_FT_TRANSFER_CALL_GAS: Gas = Gas::from_tgas(35);
+const DEFAULT_RESOLVE_TRANSFER_GAS: Gas = Gas::from_tgas(10);
+const DEFAULT_AGENT_RESPONSE_GAS: Gas = Gas::from_tgas(200);
+const DEFAULT_ON_EXECUTE_SWAPS_GAS: Gas = Gas::from_tgas(5);
+const DEFAULT_FT_METADATA_GAS: Gas = Gas::from_tgas(10);
+const DEFAULT_RESOLVE_METADATA_GAS: Gas = Gas::from_tgas(10);
+const DEFAULT_RESOLVE_FLASH_SWAP_GAS: Gas = Gas::from_tgas(20);
+// Env vars of agent runs that are set from the swap request
+pub const AGENT_CONTEXT_ENV_VARS: [&str; 3] = ["pool_id", "fee", "quote_hint"];
+
+// Parameters the owner can change with `set_config`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    // Gas of the yielded swap callback, the output transfer gas is added to it
+    pub response_gas: Gas,
+    pub ft_transfer_gas: Gas,
+    pub ft_transfer_call_gas: Gas,
+    // Gas of the callbacks that return failed transfers to deposits
+    pub resolve_transfer_gas: Gas,
+    // Gas of the callback that resolves ft_on_transfer after the swaps of the message
+    pub on_execute_swaps_gas: Gas,
+    // Gas of fetching token metadata and of the callback that caches it
+    pub ft_metadata_gas: Gas,
+    pub resolve_metadata_gas: Gas,
+    // Gas of the callback that collects the repayment of a flash swap
+    pub resolve_flash_swap_gas: Gas,
+    // Gas the agent attaches to `agent_response`, it's passed to the agent in the run_agent event
+    pub agent_response_gas: Gas,
+    // Max deviation of agent quotes from the on-chain price after fees in basis points,
+    // larger deviations are slashed. None, the default, turns the guard off
+    pub agent_tolerance: Option<u32>,
+    // Max swaps of one account waiting for the agent, unlimited if None
+    pub max_pending_swaps_per_user: Option<u32>,
+    // creator_fee of pools created without one, in basis points
+    pub default_creator_fee: u32,
+    // Paid to the deposits of agent_account_id from every swap the agent resolves, in basis points
+    pub agent_fee: u32,
+    pub agent_fee_side: AgentFeeSide,
+    // Account of the NEAR AI runner paid with the NEAR attached to swaps. If None, the NEAR goes
+    // back to the sender and the run isn't paid
+    pub agent_runner_id: Option<AccountId>,
+    // Passed to the agent runs in the run_agent event
+    pub agent_max_iterations: Option<u8>,
+    pub agent_thread_id: Option<String>,
+    pub agent_env_vars: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            response_gas: MIN_RESPONSE_GAS,
+            ft_transfer_gas: GAS_FOR_FT_TRANSFER,
+            ft_transfer_call_gas: DEFAULT_FT_TRANSFER_CALL_GAS,
+            resolve_transfer_gas: DEFAULT_RESOLVE_TRANSFER_GAS,
+            on_execute_swaps_gas: DEFAULT_ON_EXECUTE_SWAPS_GAS,
+            ft_metadata_gas: DEFAULT_FT_METADATA_GAS,
+            resolve_metadata_gas: DEFAULT_RESOLVE_METADATA_GAS,
+            resolve_flash_swap_gas: DEFAULT_RESOLVE_FLASH_SWAP_GAS,
+            agent_response_gas: DEFAULT_AGENT_RESPONSE_GAS,
+            agent_tolerance: None,
+            max_pending_swaps_per_user: None,
+            default_creator_fee: 0,
+            agent_fee: 0,
+            agent_fee_side: AgentFeeSide::default(),
+            agent_runner_id: None,
+            agent_max_iterations: None,
+            agent_thread_id: None,
+            agent_env_vars: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    fn assert_valid(&self) {
+        let invalid = |reason: &str| ContractError::InvalidConfig {
+            reason: reason.to_string(),
+        };
+
+        require(
+            self.response_gas >= MIN_RESPONSE_GAS,
+            invalid("response_gas is less than MIN_RESPONSE_GAS"),
+        );
+        for (gas, name) in [
+            (self.ft_transfer_gas, "ft_transfer_gas"),
+            (self.ft_transfer_call_gas, "ft_transfer_call_gas"),
+            (self.resolve_transfer_gas, "resolve_transfer_gas"),
+            (self.on_execute_swaps_gas, "on_execute_swaps_gas"),
+            (self.ft_metadata_gas, "ft_metadata_gas"),
+            (self.resolve_metadata_gas, "resolve_metadata_gas"),
+            (self.resolve_flash_swap_gas, "resolve_flash_swap_gas"),
+        ] {
+            require(gas.as_gas() > 0, invalid(&format!("{} is zero", name)));
+        }
+        // calls that are followed by a callback have to fit both into one function call
+        for (gas, callback_gas, name) in [
+            (
+                self.ft_metadata_gas,
+                self.resolve_metadata_gas,
+                "token metadata",
+            ),
+            (
+                self.ft_transfer_call_gas,
+                self.resolve_flash_swap_gas,
+                "flash swap",
+            ),
+        ] {
+            require(
+                gas.checked_add(callback_gas)
+                    .is_some_and(|gas| gas <= MAX_GAS),
+                invalid(&format!("{} gas is more than 300 TGas", name)),
+            );
+        }
+        require(
+            self.on_execute_swaps_gas <= MAX_GAS,
+            invalid("on_execute_swaps_gas is more than 300 TGas"),
+        );
+        // the yielded callback sends the output with ft_transfer_call in the worst case
+        let max_callback_gas = self
+            .response_gas
+            .checked_add(self.ft_transfer_call_gas)
+            .and_then(|gas| gas.checked_add(self.resolve_transfer_gas));
+        require(
+            max_callback_gas.is_some_and(|gas| gas <= MAX_GAS),
+            invalid("swap callback gas is more than 300 TGas"),
+        );
+        require(
+            self.agent_response_gas.as_gas() > 0 && self.agent_response_gas <= MAX_GAS,
+            invalid("agent_response_gas must be from 1 gas to 300 TGas"),
+        );
+
+        require(
+            self.agent_tolerance
+                .is_none_or(|tolerance| tolerance <= fees::FEE_DIVISOR),
+            invalid("agent_tolerance is more than 100%"),
+        );
+        require(
+            self.max_pending_swaps_per_user != Some(0),
+            invalid("max_pending_swaps_per_user is zero"),
+        );
+        require(
+            self.agent_max_iterations != Some(0),
+            invalid("agent_max_iterations is zero"),
+        );
+        for key in AGENT_CONTEXT_ENV_VARS {
+            require(
+                !self.agent_env_vars.contains_key(key),
+                invalid(&format!(
+                    "agent env var {} is set from the swap request",
+                    key
+                )),
+            );
+        }
+        require(
+            self.default_creator_fee <= fees::MAX_CREATOR_FEE,
+            ContractError::CreatorFeeTooHigh {
+                max_fee: fees::MAX_CREATOR_FEE,
+            },
+        );
+        require(
+            self.agent_fee <= fees::MAX_AGENT_FEE,
+            invalid(&format!("agent_fee is more than {}", fees::MAX_AGENT_FEE)),
+        );
+    }
+}
+
+impl Contract {
+    // Count the swaps of the sender waiting for the agent, up to max_pending_swaps_per_user
+    pub fn internal_add_user_pending_swap(&mut self, sender_id: &AccountId) {
+        let count = self.user_pending_swaps.get(sender_id).unwrap_or(0) + 1;
+        if let Some(max_pending_swaps) = self.config.max_pending_swaps_per_user {
+            require(
+                count <= max_pending_swaps,
+                ContractError::TooManyPendingSwaps { max_pending_swaps },
+            );
+        }
+        self.user_pending_swaps.insert(sender_id, &count);
+    }
+
+    pub fn internal_remove_user_pending_swap(&mut self, sender_id: &AccountId) {
+        match self.user_pending_swaps.get(sender_id).unwrap_or(0) {
+            0 | 1 => self.user_pending_swaps.remove(sender_id),
+            count => self.user_pending_swaps.insert(sender_id, &(count - 1)),
+        };
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn set_config(&mut self, config: Config) {
+        config.assert_valid();
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    pub fn get_user_pending_swaps(&self, account_id: AccountId) -> u32 {
+        self.user_pending_swaps.get(&account_id).unwrap_or(0)
+    }
+}
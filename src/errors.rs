@@ -0,0 +1,363 @@
+// Contract errors with stable codes. Every panic message starts with "E<code>: ",
+// so the agent and frontends can branch on the code instead of parsing the text.
+use crate::math::MathError;
+use near_sdk::{env, AccountId, FunctionError};
+use std::fmt;
+
+type Balance = u128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ContractError {
+    // Pools
+    PoolNotFound,
+    PoolAlreadyExists,
+    InitialReservesTooSmall {
+        min_reserve: Balance,
+    },
+    TokenNotInPool,
+    TokenNotWhitelisted {
+        token_id: AccountId,
+    },
+    PoolCreationFeeNotPaid {
+        amount: Balance,
+    },
+    CreatorFeeTooHigh {
+        max_fee: u32,
+    },
+    InvalidTwapWindow,
+    NotEnoughObservations,
+    PoolLocked,
+    FlashSwapNotFound,
+    FlashDebtOutstanding,
+    FlashDebtNotFound,
+    ReservedLiquidity,
+
+    // Deposits
+    NotEnoughTokenA,
+    NotEnoughTokenB,
+    InsufficientDeposit {
+        token_id: AccountId,
+        balance: Balance,
+        amount: Balance,
+    },
+    NothingToWithdraw,
+    InsufficientStorageDeposit {
+        required: Balance,
+    },
+
+    // Liquidity
+    LiquidityTooSmall,
+    SharesBelowMinimum {
+        shares: Balance,
+        min_shares: Balance,
+    },
+    InsufficientShares,
+    AmountBelowMinimum {
+        amount: Balance,
+        min_amount: Balance,
+    },
+
+    // Messages and swaps
+    InvalidMessage,
+    ZeroActionAmount,
+    ActionsOverspend,
+    SwapRequestNotFound,
+    BatchPoolMismatch,
+    InvalidClearingPrice,
+    ReferralFeeTooHigh {
+        max_fee: u32,
+    },
+    TooManyPendingSwaps {
+        max_pending_swaps: u32,
+    },
+
+    // Agent and quotes
+    IllegalAgent,
+    UnableToResume,
+    AgentPublicKeyNotSet,
+    InvalidPublicKey,
+    QuoteSenderMismatch,
+    QuoteTokenMismatch,
+    QuoteAmountMismatch,
+    QuoteExpired,
+    QuoteNonceUsed,
+    InvalidSignature,
+    InsufficientBond {
+        bonded: Balance,
+    },
+
+    // Math
+    ZeroAmount,
+    InsufficientLiquidity,
+    Overflow,
+
+    // Config
+    InvalidConfig {
+        reason: String,
+    },
+
+    // State
+    StateNotFound,
+    UnknownStateVersion {
+        version: u32,
+    },
+    DepositsV0NotFound,
+
+    // Upgrade
+    NotUpgradeAuthority,
+    UpgradeTimeLocked,
+    EmptyCode,
+    StagedCodeNotFound,
+    StagedCodeLocked {
+        available_at: u64,
+    },
+    StagedUpgradeConfigNotFound,
+    StagedUpgradeConfigLocked {
+        available_at: u64,
+    },
+}
+
+impl ContractError {
+    pub fn code(&self) -> u16 {
+        match self {
+            ContractError::PoolNotFound => 101,
+            ContractError::PoolAlreadyExists => 102,
+            ContractError::InitialReservesTooSmall { .. } => 103,
+            ContractError::TokenNotInPool => 104,
+            ContractError::TokenNotWhitelisted { .. } => 105,
+            ContractError::PoolCreationFeeNotPaid { .. } => 106,
+            ContractError::CreatorFeeTooHigh { .. } => 107,
+            ContractError::InvalidTwapWindow => 108,
+            ContractError::NotEnoughObservations => 109,
+            ContractError::PoolLocked => 110,
+            ContractError::FlashSwapNotFound => 111,
+            ContractError::FlashDebtOutstanding => 112,
+            ContractError::ReservedLiquidity => 113,
+            ContractError::FlashDebtNotFound => 115,
+
+            ContractError::NotEnoughTokenA => 201,
+            ContractError::NotEnoughTokenB => 202,
+            ContractError::InsufficientDeposit { .. } => 203,
+            ContractError::NothingToWithdraw => 204,
+            ContractError::InsufficientStorageDeposit { .. } => 205,
+
+            ContractError::LiquidityTooSmall => 301,
+            ContractError::SharesBelowMinimum { .. } => 302,
+            ContractError::InsufficientShares => 303,
+            ContractError::AmountBelowMinimum { .. } => 304,
+
+            ContractError::InvalidMessage => 401,
+            ContractError::ZeroActionAmount => 402,
+            ContractError::ActionsOverspend => 403,
+            ContractError::SwapRequestNotFound => 404,
+            ContractError::BatchPoolMismatch => 405,
+            ContractError::InvalidClearingPrice => 406,
+            ContractError::ReferralFeeTooHigh { .. } => 407,
+            ContractError::TooManyPendingSwaps { .. } => 408,
+
+            ContractError::IllegalAgent => 501,
+            ContractError::UnableToResume => 502,
+            ContractError::AgentPublicKeyNotSet => 503,
+            ContractError::InvalidPublicKey => 504,
+            ContractError::QuoteSenderMismatch => 505,
+            ContractError::QuoteTokenMismatch => 506,
+            ContractError::QuoteAmountMismatch => 507,
+            ContractError::QuoteExpired => 508,
+            ContractError::QuoteNonceUsed => 509,
+            ContractError::InvalidSignature => 510,
+            ContractError::InsufficientBond { .. } => 511,
+
+            ContractError::ZeroAmount => 601,
+            ContractError::InsufficientLiquidity => 602,
+            ContractError::Overflow => 603,
+
+            ContractError::InvalidConfig { .. } => 701,
+
+            ContractError::StateNotFound => 801,
+            ContractError::UnknownStateVersion { .. } => 802,
+            ContractError::DepositsV0NotFound => 803,
+
+            ContractError::NotUpgradeAuthority => 901,
+            ContractError::UpgradeTimeLocked => 902,
+            ContractError::EmptyCode => 903,
+            ContractError::StagedCodeNotFound => 904,
+            ContractError::StagedCodeLocked { .. } => 905,
+            ContractError::StagedUpgradeConfigNotFound => 906,
+            ContractError::StagedUpgradeConfigLocked { .. } => 907,
+        }
+    }
+}
+
+impl fmt::Display for ContractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "E{}: ", self.code())?;
+        match self {
+            ContractError::PoolNotFound => write!(f, "Pool not found"),
+            ContractError::PoolAlreadyExists => write!(f, "Pool already exists"),
+            ContractError::InitialReservesTooSmall { min_reserve } => {
+                write!(f, "Initial reserves must be at least {}", min_reserve)
+            }
+            ContractError::TokenNotInPool => write!(f, "Token is not in the pool"),
+            ContractError::TokenNotWhitelisted { token_id } => {
+                write!(f, "Token {} is not whitelisted", token_id)
+            }
+            ContractError::PoolCreationFeeNotPaid { amount } => {
+                write!(f, "Pool creation fee of {} yoctoNEAR is not paid", amount)
+            }
+            ContractError::CreatorFeeTooHigh { max_fee } => {
+                write!(f, "Creator fee can't be more than {}", max_fee)
+            }
+            ContractError::InvalidTwapWindow => write!(f, "TWAP window must be positive"),
+            ContractError::NotEnoughObservations => {
+                write!(f, "Not enough price observations for the TWAP window")
+            }
+            ContractError::PoolLocked => write!(f, "Pool is locked by a flash swap"),
+            ContractError::FlashSwapNotFound => write!(f, "Flash swap not found"),
+            ContractError::FlashDebtOutstanding => {
+                write!(f, "Flash debt has to be repaid before the next flash swap")
+            }
+            ContractError::FlashDebtNotFound => write!(f, "No flash debt"),
+            ContractError::ReservedLiquidity => {
+                write!(f, "Liquidity is reserved for pending swaps")
+            }
+
+            ContractError::NotEnoughTokenA => write!(f, "Need to deposit tokens A"),
+            ContractError::NotEnoughTokenB => write!(f, "Need to deposit tokens B"),
+            ContractError::InsufficientDeposit {
+                token_id,
+                balance,
+                amount,
+            } => write!(
+                f,
+                "Insufficient deposit of {}: {} < {}",
+                token_id, balance, amount
+            ),
+            ContractError::NothingToWithdraw => write!(f, "Nothing to withdraw"),
+            ContractError::InsufficientStorageDeposit { required } => {
+                write!(f, "Storage deposit of {} yoctoNEAR is required", required)
+            }
+
+            ContractError::LiquidityTooSmall => write!(f, "Liquidity amount is too small"),
+            ContractError::SharesBelowMinimum { shares, min_shares } => write!(
+                f,
+                "Shares {} are less than min_shares {}",
+                shares, min_shares
+            ),
+            ContractError::InsufficientShares => write!(f, "Insufficient shares"),
+            ContractError::AmountBelowMinimum { amount, min_amount } => write!(
+                f,
+                "Output amount {} is less than minimum {}",
+                amount, min_amount
+            ),
+
+            ContractError::InvalidMessage => write!(f, "Failed to parse message"),
+            ContractError::ZeroActionAmount => write!(f, "Action amount must be positive"),
+            ContractError::ActionsOverspend => {
+                write!(f, "Actions spend more than the transferred amount")
+            }
+            ContractError::SwapRequestNotFound => write!(f, "Swap request not found"),
+            ContractError::BatchPoolMismatch => {
+                write!(f, "Batch contains swaps of different pools")
+            }
+            ContractError::InvalidClearingPrice => write!(f, "Illegal clearing price"),
+            ContractError::ReferralFeeTooHigh { max_fee } => {
+                write!(f, "Referral fee can't be more than {}", max_fee)
+            }
+            ContractError::TooManyPendingSwaps { max_pending_swaps } => write!(
+                f,
+                "Account can't have more than {} pending swaps",
+                max_pending_swaps
+            ),
+
+            ContractError::IllegalAgent => write!(f, "Illegal agent account_id"),
+            ContractError::UnableToResume => write!(f, "Unable to resume promise"),
+            ContractError::AgentPublicKeyNotSet => write!(f, "Agent public key is not set"),
+            ContractError::InvalidPublicKey => write!(f, "Agent public key must be ed25519"),
+            ContractError::QuoteSenderMismatch => {
+                write!(f, "Quote was issued for another account")
+            }
+            ContractError::QuoteTokenMismatch => write!(f, "Quote token_in mismatch"),
+            ContractError::QuoteAmountMismatch => write!(f, "Quote amount_in mismatch"),
+            ContractError::QuoteExpired => write!(f, "Quote expired"),
+            ContractError::QuoteNonceUsed => write!(f, "Quote nonce already used"),
+            ContractError::InvalidSignature => write!(f, "Invalid quote signature"),
+            ContractError::InsufficientBond { bonded } => {
+                write!(f, "Bonded amount is only {}", bonded)
+            }
+
+            ContractError::ZeroAmount => write!(f, "Zero amount"),
+            ContractError::InsufficientLiquidity => write!(f, "Insufficient liquidity"),
+            ContractError::Overflow => write!(f, "Math overflow"),
+
+            ContractError::InvalidConfig { reason } => write!(f, "Invalid config: {}", reason),
+
+            ContractError::StateNotFound => write!(f, "Contract state not found"),
+            ContractError::UnknownStateVersion { version } => {
+                write!(f, "Unknown state version {}", version)
+            }
+            ContractError::DepositsV0NotFound => write!(f, "No version 0 deposits"),
+
+            ContractError::NotUpgradeAuthority => {
+                write!(f, "Only the contract account or the DAO can upgrade")
+            }
+            ContractError::UpgradeTimeLocked => {
+                write!(f, "Upgrades are time locked, the code has to be staged")
+            }
+            ContractError::EmptyCode => write!(f, "Code is empty"),
+            ContractError::StagedCodeNotFound => write!(f, "No staged code"),
+            ContractError::StagedCodeLocked { available_at } => {
+                write!(f, "Staged code can be deployed at {}", available_at)
+            }
+            ContractError::StagedUpgradeConfigNotFound => write!(f, "No staged upgrade config"),
+            ContractError::StagedUpgradeConfigLocked { available_at } => {
+                write!(
+                    f,
+                    "Staged upgrade config can be applied at {}",
+                    available_at
+                )
+            }
+        }
+    }
+}
+
+impl FunctionError for ContractError {
+    fn panic(&self) -> ! {
+        env::panic_str(&self.to_string())
+    }
+}
+
+impl From<MathError> for ContractError {
+    fn from(error: MathError) -> Self {
+        match error {
+            MathError::ZeroAmount => ContractError::ZeroAmount,
+            MathError::InsufficientLiquidity => ContractError::InsufficientLiquidity,
+            MathError::Overflow => ContractError::Overflow,
+        }
+    }
+}
+
+pub fn require(condition: bool, error: ContractError) {
+    if !condition {
+        error.panic()
+    }
+}
+
+pub trait UnwrapOrPanic<T> {
+    fn unwrap_or_panic(self) -> T;
+}
+
+impl<T, E: Into<ContractError>> UnwrapOrPanic<T> for Result<T, E> {
+    fn unwrap_or_panic(self) -> T {
+        self.unwrap_or_else(|error| error.into().panic())
+    }
+}
+
+pub trait OrPanic<T> {
+    fn or_panic(self, error: ContractError) -> T;
+}
+
+impl<T> OrPanic<T> for Option<T> {
+    fn or_panic(self, error: ContractError) -> T {
+        self.unwrap_or_else(|| error.panic())
+    }
+}
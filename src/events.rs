@@ -21,6 +21,17 @@ pub mod emit {
         pub amount: Option<u128>,
     }
 
+    // Parameters of the agent run besides the message
+    #[derive(Default)]
+    pub struct AgentRun {
+        pub max_iterations: Option<u8>,
+        pub thread_id: Option<String>,
+        // JSON object with the env vars of the run
+        pub env_vars: Option<String>,
+        // yoctoNEAR paid to the agent runner
+        pub amount: Option<u128>,
+    }
+
     fn log_event<T: Serialize>(event: &str, data: T) {
         let event = json!({
             "standard": "nearai",
@@ -32,24 +43,53 @@ pub mod emit {
         log!("EVENT_JSON:{}", event.to_string());
     }
 
-    pub fn run_agent(agent: &str, message: &String, request_id: Option<CryptoHash>) {
+    pub fn run_agent(
+        agent: &str,
+        message: &String,
+        request_id: Option<CryptoHash>,
+        referral_id: &Option<AccountId>,
+        run: &AgentRun,
+    ) {
         log_event(
             "run_agent",
             AgentData {
                 message,
                 agent: &agent.to_string(),
                 request_id,
-                max_iterations: &None,
-                thread_id: &None,
-                env_vars: &None,
+                max_iterations: &run.max_iterations,
+                thread_id: &run.thread_id,
+                env_vars: &run.env_vars,
                 signer_id: &env::predecessor_account_id(),
-                referral_id: &None,
-                amount: None,
+                referral_id,
+                amount: run.amount,
             },
         );
     }
 }
 
+pub mod u128_dec_format {
+    use near_sdk::serde::Serializer;
+
+    pub fn serialize<S>(num: &u128, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&num.to_string())
+    }
+}
+
+pub mod u256_dec_format {
+    use crate::math::U256;
+    use near_sdk::serde::Serializer;
+
+    pub fn serialize<S>(num: &U256, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&num.to_string())
+    }
+}
+
 pub mod option_u128_dec_format {
     use near_sdk::serde::Serializer;
 
@@ -0,0 +1,157 @@
+use crate::*;
+
+// Fees are set in basis points of amount_in
+pub const FEE_DIVISOR: u32 = 10_000;
+pub const MAX_CREATOR_FEE: u32 = 100;
+pub const MAX_REFERRAL_FEE: u32 = 100;
+pub const MAX_AGENT_FEE: u32 = 100;
+// Paid to the pool on top of the repaid flash swap amount
+pub const FLASH_SWAP_FEE: u32 = 30;
+
+// Fee charged from the pool creator, paid in NEAR or from the creator's token deposits
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum PoolCreationFee {
+    Near { amount: U128 },
+    Token { token_id: AccountId, amount: U128 },
+}
+
+// Part of a resolved swap the agent fee is taken from
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum AgentFeeSide {
+    #[default]
+    AmountIn,
+    AmountOut,
+}
+
+impl Contract {
+    // Agent fee of a swap resolved by the agent, as (part of amount_in, part of amount_out)
+    pub fn agent_fees(&self, amount_in: Balance, amount_out: Balance) -> (Balance, Balance) {
+        let fee = |amount: Balance| {
+            math::mul_div_floor(
+                amount,
+                self.config.agent_fee as Balance,
+                FEE_DIVISOR as Balance,
+            )
+            .unwrap_or_panic()
+        };
+        match self.config.agent_fee_side {
+            AgentFeeSide::AmountIn => (fee(amount_in), 0),
+            AgentFeeSide::AmountOut => (0, fee(amount_out)),
+        }
+    }
+
+    // Charge the pool creation fee. Token fees are moved to the deposits of the contract account
+    pub fn internal_charge_pool_creation_fee(&mut self, sender_id: &AccountId) {
+        match self.pool_creation_fee.clone() {
+            Some(PoolCreationFee::Near { amount }) => {
+                require(
+                    env::attached_deposit().as_yoctonear() >= amount.0,
+                    ContractError::PoolCreationFeeNotPaid { amount: amount.0 },
+                );
+            }
+            Some(PoolCreationFee::Token { token_id, amount }) => {
+                self.internal_withdraw_deposit(sender_id, &token_id, amount.0);
+                self.internal_deposit(&env::current_account_id(), &token_id, amount.0);
+            }
+            None => {}
+        }
+    }
+
+    // Creator and referral fees of amount_in with the accounts they are paid to
+    fn swap_fees(
+        &self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_in: Balance,
+        referral_id: &Option<AccountId>,
+    ) -> Vec<(AccountId, Balance)> {
+        let pool = self
+            .pools
+            .get(&get_pool_key(token_in, token_out))
+            .or_panic(ContractError::PoolNotFound);
+
+        let mut fees = vec![(pool.creator_id, pool.creator_fee)];
+        if let Some(referral_id) = referral_id {
+            fees.push((referral_id.clone(), self.referral_fee));
+        }
+
+        fees.into_iter()
+            .map(|(account_id, fee)| {
+                let fee = math::mul_div_floor(amount_in, fee as Balance, FEE_DIVISOR as Balance)
+                    .unwrap_or_panic();
+                (account_id, fee)
+            })
+            .collect()
+    }
+
+    // Part of amount_in that goes to the pool
+    pub fn swap_amount_after_fees(
+        &self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_in: Balance,
+        referral_id: &Option<AccountId>,
+    ) -> Balance {
+        self.swap_fees(token_in, token_out, amount_in, referral_id)
+            .iter()
+            .fold(amount_in, |amount_left, (_, fee)| amount_left - fee)
+    }
+
+    // Pay the creator and referral fees of amount_in to their deposits, returns the rest
+    pub fn internal_take_swap_fees(
+        &mut self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_in: Balance,
+        referral_id: &Option<AccountId>,
+    ) -> Balance {
+        let mut amount_left = amount_in;
+        for (account_id, fee) in self.swap_fees(token_in, token_out, amount_in, referral_id) {
+            if fee > 0 {
+                self.internal_deposit(&account_id, token_in, fee);
+                amount_left -= fee;
+            }
+        }
+        amount_left
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn set_pool_creation_fee(&mut self, fee: Option<PoolCreationFee>) {
+        self.pool_creation_fee = fee;
+    }
+
+    pub fn get_pool_creation_fee(&self) -> Option<PoolCreationFee> {
+        self.pool_creation_fee.clone()
+    }
+
+    // Part of amount_in of agent swaps with a referral_id paid to the referrer, in basis points
+    #[private]
+    pub fn set_referral_fee(&mut self, referral_fee: u32) {
+        require(
+            referral_fee <= MAX_REFERRAL_FEE,
+            ContractError::ReferralFeeTooHigh {
+                max_fee: MAX_REFERRAL_FEE,
+            },
+        );
+        self.referral_fee = referral_fee;
+    }
+
+    pub fn get_referral_fee(&self) -> u32 {
+        self.referral_fee
+    }
+
+    // Reserve the output at the on-chain price for pending agent swaps instead of min_amount_out
+    #[private]
+    pub fn set_reserve_expected_output(&mut self, reserve_expected_output: bool) {
+        self.reserve_expected_output = reserve_expected_output;
+    }
+}
@@ -0,0 +1,278 @@
+use crate::*;
+
+// Flash swap of a pool while the borrowed tokens are in flight, the pool is locked until the
+// transfer resolves. The fee on the whole amount_out is taken from the deposits of the borrower
+// up front, the repayment of the used part is collected when the transfer resolves
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct FlashSwap {
+    pub borrower_id: AccountId,
+    pub token_out: AccountId,
+    pub token_repay: AccountId,
+    pub amount_out: Balance,
+    pub fee: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FlashSwapView {
+    pub borrower_id: AccountId,
+    pub token_out: AccountId,
+    pub token_repay: AccountId,
+    pub amount_out: U128,
+    pub fee: U128,
+}
+
+// Repayment of a flash swap the borrower didn't deposit in time, owed to the pool
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct FlashDebt {
+    pub pool_id: String,
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+impl Pool {
+    // Amount of token_repay that restores the invariant before the loan of amount_out
+    fn flash_swap_repay_amount(
+        &self,
+        token_out: &AccountId,
+        token_repay: &AccountId,
+        amount_out: Balance,
+    ) -> Balance {
+        if token_repay == token_out {
+            return amount_out;
+        }
+        // reserve_out doesn't include amount_out while the loan is outstanding
+        let (reserve_out, reserve_in) = self.balances(token_out);
+        math::get_amount_in(amount_out, reserve_in, reserve_out + amount_out).unwrap_or_panic()
+    }
+
+    pub fn add_reserve(&mut self, token_id: &AccountId, amount: Balance) {
+        if token_id == &self.token_a {
+            self.token_a_balance = math::add(self.token_a_balance, amount).unwrap_or_panic();
+        } else {
+            self.token_b_balance = math::add(self.token_b_balance, amount).unwrap_or_panic();
+        }
+    }
+}
+
+// FLASH_SWAP_FEE of the amount paid with the fee, rounded up
+fn flash_swap_fee(amount: Balance) -> Balance {
+    math::mul_div_ceil(
+        amount,
+        fees::FLASH_SWAP_FEE as Balance,
+        (fees::FEE_DIVISOR - fees::FLASH_SWAP_FEE) as Balance,
+    )
+    .unwrap_or_panic()
+}
+
+impl Contract {
+    pub fn is_pool_locked(&self, pool_key: &String) -> bool {
+        self.flash_swaps.contains_key(pool_key)
+    }
+
+    // Pool that can be changed, panics while it's locked by a flash swap
+    pub fn get_unlocked_pool(&self, pool_key: &String) -> Pool {
+        require(!self.is_pool_locked(pool_key), ContractError::PoolLocked);
+        self.pools
+            .get(pool_key)
+            .or_panic(ContractError::PoolNotFound)
+    }
+
+    // Move up to amount of token_id from the deposits of the borrower into the pool,
+    // returns the part that couldn't be paid
+    fn internal_collect_flash_repayment(
+        &mut self,
+        borrower_id: &AccountId,
+        pool: &mut Pool,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let balance = self
+            .get_deposits(borrower_id)
+            .tokens
+            .get(token_id)
+            .unwrap_or(0);
+        let paid = std::cmp::min(balance, amount);
+        if paid > 0 {
+            self.internal_withdraw_deposit(borrower_id, token_id, paid);
+            pool.add_reserve(token_id, paid);
+        }
+        amount - paid
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Borrow amount_out of token_out from the pool of token_out and token_other. It's sent to
+    // the caller with `ft_transfer_call` and msg. The fee on amount_out is taken from the
+    // deposits of the caller in token_repay, token_other if omitted. Before its `ft_on_transfer`
+    // resolves, the caller has to return amount_out or deposit its repayment in token_repay.
+    pub fn flash_swap(
+        &mut self,
+        token_out: AccountId,
+        token_other: AccountId,
+        amount_out: U128,
+        token_repay: Option<AccountId>,
+        msg: String,
+    ) -> Promise {
+        let borrower_id = env::predecessor_account_id();
+        require(amount_out.0 > 0, ContractError::ZeroAmount);
+        require(
+            !self.flash_debts.contains_key(&borrower_id),
+            ContractError::FlashDebtOutstanding,
+        );
+        let token_repay = token_repay.unwrap_or_else(|| token_other.clone());
+        require(
+            token_repay == token_out || token_repay == token_other,
+            ContractError::TokenNotInPool,
+        );
+
+        let pool_key = get_pool_key(&token_out, &token_other);
+        let mut pool = self.get_unlocked_pool(&pool_key);
+
+        // the oracle is checkpointed before the reserves are lent, it's not updated while
+        // the pool is locked
+        self.internal_update_oracle(&pool_key, &mut pool);
+        let (reserve_out, _) = pool.balances(&token_out);
+        let reserve_out = math::sub_reserve(reserve_out, amount_out.0).unwrap_or_panic();
+        if token_out == pool.token_a {
+            pool.token_a_balance = reserve_out;
+        } else {
+            pool.token_b_balance = reserve_out;
+        }
+        pool.assert_reserved_covered();
+        self.pools.insert(&pool_key, &pool);
+
+        let fee =
+            flash_swap_fee(pool.flash_swap_repay_amount(&token_out, &token_repay, amount_out.0));
+        self.internal_withdraw_deposit(&borrower_id, &token_repay, fee);
+        self.flash_swaps.insert(
+            &pool_key,
+            &FlashSwap {
+                borrower_id: borrower_id.clone(),
+                token_out: token_out.clone(),
+                token_repay,
+                amount_out: amount_out.0,
+                fee,
+            },
+        );
+
+        ext_ft::ext(token_out)
+            .with_static_gas(self.config.ft_transfer_call_gas)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .ft_transfer_call(borrower_id, amount_out, Some("Flash swap".to_string()), msg)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(self.config.resolve_flash_swap_gas)
+                    .on_flash_swap(pool_key),
+            )
+    }
+
+    // Return what the borrower didn't use to the pool, collect the repayment of the used part
+    // from the deposits of the borrower and pay the fee to the pool. The pool is unlocked in any
+    // case, a repayment the borrower didn't deposit becomes its flash debt
+    #[private]
+    pub fn on_flash_swap(
+        &mut self,
+        pool_key: String,
+        #[callback_result] used_amount: Result<U128, PromiseError>,
+    ) {
+        let flash_swap = self
+            .flash_swaps
+            .get(&pool_key)
+            .or_panic(ContractError::FlashSwapNotFound);
+        let used_amount = used_amount
+            .map(|used| used.0.min(flash_swap.amount_out))
+            .unwrap_or(0);
+
+        let mut pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
+        let FlashSwap {
+            borrower_id,
+            token_out,
+            token_repay,
+            amount_out,
+            fee,
+        } = flash_swap;
+        pool.add_reserve(&token_out, amount_out - used_amount);
+        let repay_amount = if used_amount > 0 {
+            pool.flash_swap_repay_amount(&token_out, &token_repay, used_amount)
+        } else {
+            0
+        };
+        let unpaid = self.internal_collect_flash_repayment(
+            &borrower_id,
+            &mut pool,
+            &token_repay,
+            repay_amount,
+        );
+        pool.add_reserve(&token_repay, fee);
+        self.pools.insert(&pool_key, &pool);
+        self.flash_swaps.remove(&pool_key);
+
+        if unpaid > 0 {
+            log!(
+                "Flash swap is not repaid, {} {} is owed by {}",
+                unpaid,
+                token_repay,
+                borrower_id
+            );
+            self.flash_debts.insert(
+                &borrower_id,
+                &FlashDebt {
+                    pool_id: pool_key,
+                    token_id: token_repay,
+                    amount: U128(unpaid),
+                },
+            );
+        }
+    }
+
+    // Pay the flash debt of the caller from its deposits into the pool
+    pub fn repay_flash_debt(&mut self) -> Option<FlashDebt> {
+        let borrower_id = env::predecessor_account_id();
+        let mut debt = self
+            .flash_debts
+            .get(&borrower_id)
+            .or_panic(ContractError::FlashDebtNotFound);
+        let mut pool = self.get_unlocked_pool(&debt.pool_id);
+        self.internal_update_oracle(&debt.pool_id, &mut pool);
+        let unpaid = self.internal_collect_flash_repayment(
+            &borrower_id,
+            &mut pool,
+            &debt.token_id,
+            debt.amount.0,
+        );
+        self.pools.insert(&debt.pool_id, &pool);
+
+        if unpaid == 0 {
+            self.flash_debts.remove(&borrower_id);
+            None
+        } else {
+            debt.amount = U128(unpaid);
+            self.flash_debts.insert(&borrower_id, &debt);
+            Some(debt)
+        }
+    }
+
+    pub fn get_flash_swap(&self, pool_id: String) -> Option<FlashSwapView> {
+        self.flash_swaps
+            .get(&pool_id)
+            .map(|flash_swap| FlashSwapView {
+                borrower_id: flash_swap.borrower_id,
+                token_out: flash_swap.token_out,
+                token_repay: flash_swap.token_repay,
+                amount_out: U128(flash_swap.amount_out),
+                fee: U128(flash_swap.fee),
+            })
+    }
+
+    pub fn get_flash_debt(&self, account_id: AccountId) -> Option<FlashDebt> {
+        self.flash_debts.get(&account_id)
+    }
+}
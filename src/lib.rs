@@ -1,38 +1,123 @@
-use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base64VecU8, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, ext_contract, log, near_bindgen, AccountId, BorshStorageKey, Gas, NearToken,
-    PanicOnDefault, PromiseOrValue,
+    assert_one_yocto, env, ext_contract, log, near_bindgen, AccountId, BorshStorageKey,
+    FunctionError, Gas, NearToken, PanicOnDefault, Promise, PromiseOrValue, PublicKey,
 };
 use schemars::JsonSchema;
 use std::convert::TryInto;
 
-use near_sdk::{GasWeight, PromiseError};
+use near_sdk::{GasWeight, PromiseError, PromiseIndex};
 use serde_json::json;
 
 mod agent;
+mod batch;
+mod config;
+mod errors;
 mod events;
+mod fees;
+mod flash;
+mod math;
+mod migration;
+mod oracle;
+mod pricing;
+mod quote;
+mod staking;
+mod stats;
+mod storage;
+mod swap;
+mod tokens;
+mod upgrade;
+mod zap;
+
+pub use agent::PendingSwap;
+pub use batch::{ClearingPrice, SwapBatch};
+pub use config::Config;
+pub use errors::ContractError;
+use errors::{require, OrPanic, UnwrapOrPanic};
+pub use fees::{AgentFeeSide, PoolCreationFee};
+use flash::FlashSwap;
+pub use flash::{FlashDebt, FlashSwapView};
+use math::U256;
+pub use migration::{ContractV0, PoolV0, VersionedContract, STATE_VERSION};
+use oracle::Observations;
+pub use oracle::{Observation, TwapView};
+pub use pricing::PricingMode;
+use pricing::SwapResult;
+pub use quote::SwapQuote;
+use staking::AgentBond;
+pub use staking::{AgentBondView, BondAsset, Unbonding};
+use stats::AgentStats;
+pub use stats::AgentStatsView;
+use storage::StorageBalance;
+pub use storage::StorageBalanceView;
+pub use swap::{ext_amm, Amm};
+pub use tokens::TokenMetadata;
+pub use upgrade::{StagedCodeView, StagedUpgradeConfig, UpgradeConfig};
+pub use zap::Zap;
 
 type Balance = u128;
 pub type CryptoHash = [u8; 32];
 const TGAS: u64 = 1_000_000_000_000;
 const GAS_FOR_FT_TRANSFER: Gas = Gas::from_gas(10 * TGAS);
-const INIT_SHARES_SUPPLY: u128 = 1_000_000_000_000_000;
+// Part of the initial shares that is never assigned to anyone. Shares are minted as
+// sqrt(reserve_a * reserve_b), so the reserves left for them keep a product of at least
+// MIN_LOCKED_SHARES^2 and the pool can't be emptied
+const MIN_LOCKED_SHARES: u128 = 1_000;
+const MIN_INITIAL_RESERVE: u128 = 1_000;
 pub const MIN_RESPONSE_GAS: Gas = Gas::from_tgas(30);
 pub const DATA_ID_REGISTER: u64 = 37;
 
 #[derive(BorshSerialize, BorshStorageKey)]
 #[borsh(crate = "near_sdk::borsh")]
 enum StorageKey {
-    Pools,
-    Deposits,
-    TokenDeposits { account_id: AccountId },
-    Shares { pool_key: String },
+    // Pools and deposits of the version 0 state, kept until they are migrated
+    PoolsV0,
+    DepositsV0,
+    #[allow(dead_code)]
+    TokenDepositsV0 {
+        account_id: AccountId,
+    },
+    Shares {
+        pool_key: String,
+    },
+    QuoteNonces,
+    PendingSwaps,
+    PendingDataIds,
+    SwapBatches,
+    WhitelistedTokens,
+    UserWhitelistedTokens {
+        account_id: AccountId,
+    },
+    UserWhitelists,
+    TokenMetadata,
+    Observations,
+    FlashSwaps,
+    AgentStats,
+    AgentBonds,
+    UserPendingSwaps,
+    PoolsV1,
+    StateVersion,
+    UpgradeConfig,
+    StagedCode,
+    StorageBalances,
+    DepositsV1,
+    TokenDepositsV1 {
+        account_id: AccountId,
+    },
+    StagedUpgradeConfig,
+    FlashDebts,
+    ObservationSlots {
+        pool_key: String,
+    },
+    OpenSwapBatches,
 }
 
+// Actions of one message are executed in order. The transferred amount is deposited first,
+// every action takes its `amount` of it (all that is left if omitted) and the rest stays in deposits.
 #[derive(Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub enum Action {
@@ -40,10 +125,25 @@ pub enum Action {
     Swap {
         token_out: AccountId,
         min_amount_out: U128,
+        amount: Option<U128>,
+        receiver_id: Option<AccountId>,
+        msg: Option<String>,
+        referral_id: Option<AccountId>,
     },
     AddLiquidity {
         token_other: AccountId,
         amount_other: U128,
+        amount: Option<U128>,
+        min_shares: Option<U128>,
+    },
+    SwapWithQuote {
+        quote: SwapQuote,
+        signature: Base64VecU8,
+    },
+    ZapIn {
+        token_other: AccountId,
+        min_shares: U128,
+        amount: Option<U128>,
     },
 }
 
@@ -53,7 +153,8 @@ pub enum TokenReceiverMessage {
     Execute { actions: Vec<Action> },
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
 #[serde(crate = "near_sdk::serde")]
 pub struct SwapRequest {
     #[schemars(with = "String")]
@@ -66,6 +167,21 @@ pub struct SwapRequest {
     pub amount_in: U128,
     #[schemars(with = "String")]
     pub min_amount_out: U128,
+    // Account that receives the output, sender_id if omitted
+    #[schemars(with = "Option<String>")]
+    pub receiver_id: Option<AccountId>,
+    // Output is sent with `ft_transfer_call` and this msg if set
+    pub msg: Option<String>,
+    // Account that receives the referral fee
+    #[schemars(with = "Option<String>")]
+    pub referral_id: Option<AccountId>,
+    // yoctoNEAR attached to pay the agent runner, it's sent to the agent_runner_id of the config
+    #[schemars(with = "Option<String>")]
+    pub agent_payment: Option<U128>,
+    // Rest of the zap this swap is the swap leg of, the output stays in deposits for it
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub zap: Option<Zap>,
 }
 
 #[ext_contract(ext_ft)]
@@ -78,6 +194,7 @@ pub trait FungibleToken {
         memo: Option<String>,
         msg: String,
     ) -> PromiseOrValue<U128>;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
@@ -89,6 +206,115 @@ pub struct Pool {
     token_b_balance: Balance,
     total_shares: Balance,
     shares: UnorderedMap<AccountId, Balance>,
+    creator_id: AccountId,
+    // Block timestamp in nanoseconds
+    created_at: u64,
+    // Part of amount_in of agent and quote swaps paid to the creator, in basis points
+    creator_fee: u32,
+    // TWAP accumulators of the spot prices, updated before every reserves change
+    price_a_cumulative: U256,
+    price_b_cumulative: U256,
+    // Block timestamp in seconds
+    oracle_updated_at: u64,
+    // Output reserved for pending agent swaps, the reserves can't go below it
+    reserved_a: Balance,
+    reserved_b: Balance,
+    pricing_mode: PricingMode,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolView {
+    pub pool_id: String,
+    pub token_a: AccountId,
+    pub token_b: AccountId,
+    pub token_a_balance: U128,
+    pub token_b_balance: U128,
+    pub total_shares: U128,
+    pub creator_id: AccountId,
+    pub created_at: U64,
+    pub creator_fee: u32,
+    pub reserved_a: U128,
+    pub reserved_b: U128,
+    pub pricing_mode: PricingMode,
+}
+
+impl From<&Pool> for PoolView {
+    fn from(pool: &Pool) -> Self {
+        PoolView {
+            pool_id: get_pool_key(&pool.token_a, &pool.token_b),
+            token_a: pool.token_a.clone(),
+            token_b: pool.token_b.clone(),
+            token_a_balance: U128(pool.token_a_balance),
+            token_b_balance: U128(pool.token_b_balance),
+            total_shares: U128(pool.total_shares),
+            creator_id: pool.creator_id.clone(),
+            created_at: U64(pool.created_at),
+            creator_fee: pool.creator_fee,
+            reserved_a: U128(pool.reserved_a),
+            reserved_b: U128(pool.reserved_b),
+            pricing_mode: pool.pricing_mode,
+        }
+    }
+}
+
+impl Pool {
+    fn balances(&self, token_in: &AccountId) -> (Balance, Balance) {
+        if token_in == &self.token_a {
+            (self.token_a_balance, self.token_b_balance)
+        } else {
+            (self.token_b_balance, self.token_a_balance)
+        }
+    }
+
+    fn reserved(&self, token_id: &AccountId) -> Balance {
+        if token_id == &self.token_a {
+            self.reserved_a
+        } else {
+            self.reserved_b
+        }
+    }
+
+    fn set_reserved(&mut self, token_id: &AccountId, amount: Balance) {
+        if token_id == &self.token_a {
+            self.reserved_a = amount;
+        } else {
+            self.reserved_b = amount;
+        }
+    }
+
+    // Swaps, removals and flash swaps can't take the liquidity reserved for pending swaps
+    fn assert_reserved_covered(&self) {
+        require(
+            self.token_a_balance > self.reserved_a && self.token_b_balance > self.reserved_b,
+            ContractError::ReservedLiquidity,
+        );
+    }
+
+    // On-chain constant product price
+    fn get_amount_out(&self, token_in: &AccountId, amount_in: Balance) -> Balance {
+        let (balance_in, balance_out) = self.balances(token_in);
+        math::get_amount_out(amount_in, balance_in, balance_out).unwrap_or_panic()
+    }
+
+    // Largest amounts up to the given ones that keep the current reserve ratio
+    fn optimal_liquidity_amounts(
+        &self,
+        max_a_amount: Balance,
+        max_b_amount: Balance,
+    ) -> (Balance, Balance) {
+        let b_amount =
+            math::quote_paired_amount(max_a_amount, self.token_a_balance, self.token_b_balance)
+                .unwrap_or_panic();
+        if b_amount <= max_b_amount {
+            (max_a_amount, b_amount)
+        } else {
+            let a_amount =
+                math::quote_paired_amount(max_b_amount, self.token_b_balance, self.token_a_balance)
+                    .unwrap_or_panic();
+            (a_amount, max_b_amount)
+        }
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
@@ -100,23 +326,79 @@ pub struct AccountDeposits {
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 #[borsh(crate = "near_sdk::borsh")]
-#[near_bindgen]
+#[near_bindgen(contract_state)]
 pub struct Contract {
     agent: String,
     agent_account_id: AccountId,
     pools: UnorderedMap<String, Pool>,
     deposits: UnorderedMap<AccountId, AccountDeposits>,
+    agent_public_key: Option<PublicKey>,
+    quote_nonces: LookupMap<AccountId, u64>,
+    next_request_id: u64,
+    pending_swaps: LookupMap<u64, PendingSwap>,
+    pending_data_ids: LookupMap<CryptoHash, u64>,
+    batch_window_blocks: u64,
+    swap_batches: LookupMap<u64, SwapBatch>,
+    open_swap_batches: LookupMap<String, u64>,
+    pool_creation_fee: Option<PoolCreationFee>,
+    referral_fee: u32,
+    reserve_expected_output: bool,
+    whitelisted_tokens: UnorderedSet<AccountId>,
+    user_whitelisted_tokens: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    token_metadata: LookupMap<AccountId, TokenMetadata>,
+    observations: LookupMap<String, Observations>,
+    flash_swaps: LookupMap<String, FlashSwap>,
+    flash_debts: LookupMap<AccountId, FlashDebt>,
+    agent_stats: LookupMap<AccountId, AgentStats>,
+    agent_bonds: LookupMap<AccountId, AgentBond>,
+    unbonding_period_sec: u64,
+    near_slash_penalty: Balance,
+    config: Config,
+    user_pending_swaps: LookupMap<AccountId, u32>,
+    // NEAR deposited for the storage of pending swaps
+    storage_balances: LookupMap<AccountId, StorageBalance>,
+    // Version 0 pools that aren't migrated yet, see `migrate_pools`
+    pools_v0: UnorderedMap<String, PoolV0>,
+    // Version 0 deposits, frozen until the contract account reconciles them
+    deposits_v0: UnorderedMap<AccountId, AccountDeposits>,
 }
 
 #[near_bindgen]
 impl Contract {
     #[init]
     pub fn new(agent: String, agent_account_id: AccountId) -> Self {
+        migration::write_state_version();
         Self {
             agent,
             agent_account_id,
-            pools: UnorderedMap::new(StorageKey::Pools),
-            deposits: UnorderedMap::new(StorageKey::Deposits),
+            pools: UnorderedMap::new(StorageKey::PoolsV1),
+            deposits: UnorderedMap::new(StorageKey::DepositsV1),
+            agent_public_key: None,
+            quote_nonces: LookupMap::new(StorageKey::QuoteNonces),
+            next_request_id: 0,
+            pending_swaps: LookupMap::new(StorageKey::PendingSwaps),
+            pending_data_ids: LookupMap::new(StorageKey::PendingDataIds),
+            batch_window_blocks: 0,
+            swap_batches: LookupMap::new(StorageKey::SwapBatches),
+            open_swap_batches: LookupMap::new(StorageKey::OpenSwapBatches),
+            pool_creation_fee: None,
+            referral_fee: 0,
+            reserve_expected_output: false,
+            whitelisted_tokens: UnorderedSet::new(StorageKey::WhitelistedTokens),
+            user_whitelisted_tokens: LookupMap::new(StorageKey::UserWhitelists),
+            token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+            observations: LookupMap::new(StorageKey::Observations),
+            flash_swaps: LookupMap::new(StorageKey::FlashSwaps),
+            flash_debts: LookupMap::new(StorageKey::FlashDebts),
+            agent_stats: LookupMap::new(StorageKey::AgentStats),
+            agent_bonds: LookupMap::new(StorageKey::AgentBonds),
+            unbonding_period_sec: staking::DEFAULT_UNBONDING_PERIOD_SEC,
+            near_slash_penalty: 0,
+            config: Config::default(),
+            user_pending_swaps: LookupMap::new(StorageKey::UserPendingSwaps),
+            storage_balances: LookupMap::new(StorageKey::StorageBalances),
+            pools_v0: UnorderedMap::new(StorageKey::PoolsV0),
+            deposits_v0: UnorderedMap::new(StorageKey::DepositsV0),
         }
     }
 
@@ -125,46 +407,108 @@ impl Contract {
         self.agent = agent;
     }
 
+    // Register the ed25519 key the agent uses to sign off-chain quotes
+    #[private]
+    pub fn set_agent_public_key(&mut self, public_key: PublicKey) {
+        require(
+            public_key.curve_type() == near_sdk::CurveType::ED25519,
+            ContractError::InvalidPublicKey,
+        );
+        self.agent_public_key = Some(public_key);
+    }
+
+    // The creator pays the pool creation fee, if set, and can take a creator_fee from swaps
+    #[payable]
     pub fn create_pool(
         &mut self,
         token_a: AccountId,
         token_a_amount: U128,
         token_b: AccountId,
         token_b_amount: U128,
+        creator_fee: Option<u32>,
     ) {
+        let initial_storage_usage = env::storage_usage();
         let sender_id = env::predecessor_account_id();
         let deposits = self.get_deposits(&sender_id);
         let balance_token_a = deposits.tokens.get(&token_a).unwrap_or(0);
-        assert!(
+        require(
             balance_token_a >= token_a_amount.0,
-            "Need to deposit tokens A"
+            ContractError::NotEnoughTokenA,
         );
         let balance_token_b = deposits.tokens.get(&token_b).unwrap_or(0);
-        assert!(
+        require(
             balance_token_b >= token_b_amount.0,
-            "Need to deposit tokens B"
+            ContractError::NotEnoughTokenB,
+        );
+
+        require(
+            token_a_amount.0 >= MIN_INITIAL_RESERVE && token_b_amount.0 >= MIN_INITIAL_RESERVE,
+            ContractError::InitialReservesTooSmall {
+                min_reserve: MIN_INITIAL_RESERVE,
+            },
+        );
+
+        let creator_fee = creator_fee.unwrap_or(self.config.default_creator_fee);
+        require(
+            creator_fee <= fees::MAX_CREATOR_FEE,
+            ContractError::CreatorFeeTooHigh {
+                max_fee: fees::MAX_CREATOR_FEE,
+            },
         );
 
         let pool_key = get_pool_key(&token_a, &token_b);
-        assert!(self.pools.get(&pool_key).is_none(), "Pool already exists");
+        require(
+            self.pools.get(&pool_key).is_none() && self.pools_v0.get(&pool_key).is_none(),
+            ContractError::PoolAlreadyExists,
+        );
+
+        self.internal_charge_pool_creation_fee(&sender_id);
+        self.internal_withdraw_deposit(&sender_id, &token_a, token_a_amount.0);
+        self.internal_withdraw_deposit(&sender_id, &token_b, token_b_amount.0);
 
         let mut shares_map = UnorderedMap::new(StorageKey::Shares {
             pool_key: pool_key.clone(),
         });
 
-        let initial_shares = INIT_SHARES_SUPPLY;
-        shares_map.insert(&env::predecessor_account_id(), &initial_shares);
+        // at least MIN_LOCKED_SHARES, as both reserves are at least MIN_INITIAL_RESERVE
+        let initial_shares = math::initial_shares(token_a_amount.0, token_b_amount.0);
+        shares_map.insert(&sender_id, &(initial_shares - MIN_LOCKED_SHARES));
 
-        let pool = Pool {
+        let mut pool = Pool {
             token_a,
             token_b,
             token_a_balance: token_a_amount.0,
             token_b_balance: token_b_amount.0,
             total_shares: initial_shares,
             shares: shares_map,
+            creator_id: sender_id.clone(),
+            created_at: env::block_timestamp(),
+            creator_fee,
+            price_a_cumulative: U256::zero(),
+            price_b_cumulative: U256::zero(),
+            oracle_updated_at: oracle::block_timestamp_sec(),
+            reserved_a: 0,
+            reserved_b: 0,
+            pricing_mode: PricingMode::default(),
         };
+        self.observations
+            .insert(&pool_key, &Observations::new(&pool_key));
+        let oracle_storage_usage = env::storage_usage();
+        self.internal_update_oracle(&pool_key, &mut pool);
+        let observation_bytes = env::storage_usage() - oracle_storage_usage;
 
         self.pools.insert(&pool_key, &pool);
+
+        // the creator pays for the storage of the pool and of all the observations the oracle
+        // keeps for it from its storage deposit, the slots that aren't written yet are charged
+        // at the size of the first one
+        let oracle_cost = env::storage_byte_cost().as_yoctonear()
+            * observation_bytes as Balance
+            * (oracle::OBSERVATIONS_CAPACITY - 1) as Balance;
+        self.internal_lock_storage(
+            &sender_id,
+            storage::storage_cost(initial_storage_usage) + oracle_cost,
+        );
     }
 
     pub fn get_pool_info(
@@ -182,6 +526,21 @@ impl Contract {
         })
     }
 
+    pub fn get_pool(&self, token_a: AccountId, token_b: AccountId) -> Option<PoolView> {
+        self.pools
+            .get(&get_pool_key(&token_a, &token_b))
+            .map(|pool| PoolView::from(&pool))
+    }
+
+    pub fn get_pools(&self, from_index: u64, limit: u64) -> Vec<PoolView> {
+        self.pools
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|pool| PoolView::from(&pool))
+            .collect()
+    }
+
     pub fn get_shares(
         &self,
         token_a: AccountId,
@@ -189,18 +548,24 @@ impl Contract {
         account_id: AccountId,
     ) -> Balance {
         let pool_key = get_pool_key(&token_a, &token_b);
-        let pool = self.pools.get(&pool_key).expect("Pool not found");
+        let pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
         pool.shares.get(&account_id).unwrap_or(0)
     }
 
     pub fn get_user_deposits(&self, account_id: AccountId) -> Vec<(AccountId, U128)> {
         self.deposits
             .get(&account_id)
-            .unwrap()
-            .tokens
-            .iter()
-            .map(|(k, v)| (k.clone(), U128(v)))
-            .collect()
+            .map(|deposits| {
+                deposits
+                    .tokens
+                    .iter()
+                    .map(|(k, v)| (k.clone(), U128(v)))
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 
     fn get_deposits(&self, account_id: &AccountId) -> AccountDeposits {
@@ -208,29 +573,129 @@ impl Contract {
             deposits
         } else {
             AccountDeposits {
-                tokens: UnorderedMap::new(StorageKey::TokenDeposits {
+                tokens: UnorderedMap::new(StorageKey::TokenDepositsV1 {
                     account_id: account_id.clone(),
                 }),
             }
         }
     }
 
+    // The storage of a token the account doesn't have in deposits yet is locked from its storage
+    // deposit for good, as deposit entries are never removed
+    fn internal_register_deposit(&mut self, account_id: &AccountId, token_id: &AccountId) {
+        let mut deposits = self.get_deposits(account_id);
+        if deposits.tokens.get(token_id).is_some() {
+            return;
+        }
+        let initial_storage_usage = env::storage_usage();
+        deposits.tokens.insert(token_id, &0);
+        self.deposits.insert(account_id, &deposits);
+        self.internal_lock_storage(account_id, storage::storage_cost(initial_storage_usage));
+    }
+
     fn internal_deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
         let mut deposits = self.get_deposits(account_id);
         let balance = deposits.tokens.get(token_id).unwrap_or(0);
-        deposits.tokens.insert(token_id, &(balance + amount));
+        let balance = math::add(balance, amount).unwrap_or_panic();
+        deposits.tokens.insert(token_id, &balance);
+        self.deposits.insert(account_id, &deposits);
+    }
+
+    fn assert_deposit(&self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let balance = self
+            .get_deposits(account_id)
+            .tokens
+            .get(token_id)
+            .unwrap_or(0);
+        if balance < amount {
+            ContractError::InsufficientDeposit {
+                token_id: token_id.clone(),
+                balance,
+                amount,
+            }
+            .panic()
+        }
+    }
+
+    fn internal_withdraw_deposit(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        self.assert_deposit(account_id, token_id, amount);
+        let mut deposits = self.get_deposits(account_id);
+        let balance = deposits.tokens.get(token_id).unwrap_or(0);
+        deposits.tokens.insert(token_id, &(balance - amount));
         self.deposits.insert(account_id, &deposits);
     }
 
+    // Withdraw tokens from the deposits of the caller, all of them if amount is omitted
+    #[payable]
+    pub fn withdraw(&mut self, token_id: AccountId, amount: Option<U128>) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let amount = amount.map(|amount| amount.0).unwrap_or_else(|| {
+            self.get_deposits(&account_id)
+                .tokens
+                .get(&token_id)
+                .unwrap_or(0)
+        });
+        require(amount > 0, ContractError::NothingToWithdraw);
+
+        self.internal_withdraw_deposit(&account_id, &token_id, amount);
+
+        ext_ft::ext(token_id.clone())
+            .with_static_gas(self.config.ft_transfer_gas)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .ft_transfer(
+                account_id.clone(),
+                U128(amount),
+                Some("Withdraw".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(self.config.resolve_transfer_gas)
+                    .on_withdraw(account_id, token_id, U128(amount)),
+            )
+    }
+
+    // Return the tokens to deposits if the transfer failed
+    #[private]
+    pub fn on_withdraw(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) {
+        if result.is_err() {
+            log!(
+                "Withdraw failed, {} {} returned to deposits",
+                amount.0,
+                token_id
+            );
+            self.internal_deposit(&account_id, &token_id, amount.0);
+        }
+    }
+
+    // Execute the actions of the message. Returns the yields of the agent swaps joined with
+    // `on_execute_swaps`, None if no swap waits for the agent
     fn internal_process_token_transfer(
         &mut self,
         sender_id: AccountId,
         token_in: AccountId,
         amount_in: Balance,
         msg: String,
-    ) -> PromiseOrValue<U128> {
+    ) -> Option<PromiseIndex> {
         let message: TokenReceiverMessage =
-            serde_json::from_str(&msg).expect("Failed to parse message");
+            serde_json::from_str(&msg).unwrap_or_else(|_| ContractError::InvalidMessage.panic());
+
+        // Deposit the amount_in sent by user in this transaction to the contract,
+        // actions below spend it from the deposits
+        self.internal_deposit(&sender_id, &token_in, amount_in);
+        let mut amount_left = amount_in;
+        let mut yields = vec![];
 
         match message {
             TokenReceiverMessage::Execute { actions } => {
@@ -239,54 +704,125 @@ impl Contract {
                         Action::Swap {
                             token_out,
                             min_amount_out,
+                            amount,
+                            receiver_id,
+                            msg,
+                            referral_id,
                         } => {
-                            // Deposit the amount_in sent by user in this transaction to the contract
-                            self.internal_deposit(&sender_id, &token_in, amount_in);
+                            let amount = take_action_amount(&mut amount_left, amount);
 
-                            // Run agent to interrupt the swap transaction
-                            self.run_agent_market_maker(
-                                sender_id.clone(),
-                                token_in.clone(),
+                            // Run agent to interrupt the swap transaction, or settle it on-chain
+                            // depending on the pricing mode of the pool. Swaps that join an open
+                            // batch are queued without a yield and settle with the batch
+                            let result = self.internal_request_swap(SwapRequest {
+                                sender_id: sender_id.clone(),
+                                token_in: token_in.clone(),
                                 token_out,
-                                amount_in,
-                                min_amount_out.0,
-                            );
+                                amount_in: U128(amount),
+                                min_amount_out,
+                                receiver_id,
+                                msg,
+                                referral_id,
+                                agent_payment: None,
+                                zap: None,
+                            });
+                            if let SwapResult::Pending(promise_idx) = result {
+                                yields.push(promise_idx);
+                            }
                         }
                         Action::Deposit {} => {
-                            self.internal_deposit(&sender_id, &token_in, amount_in);
                             log!("Deposit successfull")
                         }
                         Action::AddLiquidity {
                             token_other,
                             amount_other,
+                            amount,
+                            min_shares,
                         } => {
-                            let deposits = self.get_deposits(&sender_id);
-                            let balance_other = deposits.tokens.get(&token_other).unwrap_or(0);
-
-                            assert!(
-                                balance_other >= amount_other.0,
-                                "Insufficient balance of token_other for add_liquidity"
-                            );
+                            let amount = take_action_amount(&mut amount_left, amount);
+                            self.assert_deposit(&sender_id, &token_other, amount_other.0);
 
                             self.internal_add_liquidity(
                                 &token_in,
                                 &token_other,
-                                amount_in,
+                                amount,
                                 amount_other.0,
                                 &sender_id,
+                                min_shares.map(|shares| shares.0).unwrap_or(0),
+                            );
+                        }
+                        Action::SwapWithQuote { quote, signature } => {
+                            let amount =
+                                take_action_amount(&mut amount_left, Some(quote.amount_in));
+
+                            // Settle the swap right away against the quote signed by the agent
+                            self.internal_swap_with_quote(
+                                &sender_id, &token_in, amount, quote, signature,
+                            );
+                        }
+                        Action::ZapIn {
+                            token_other,
+                            min_shares,
+                            amount,
+                        } => {
+                            let amount = take_action_amount(&mut amount_left, amount);
+                            let result = self.internal_zap_in(
+                                &sender_id,
+                                &token_in,
+                                &token_other,
+                                amount,
+                                min_shares.0,
                             );
+                            if let SwapResult::Pending(promise_idx) = result {
+                                yields.push(promise_idx);
+                            }
                         }
                     }
                 }
             }
         }
 
-        PromiseOrValue::Value(U128(0))
+        // the transfer resolves once all swaps of the message are settled
+        let joint = match yields.as_slice() {
+            [] => return None,
+            [promise_idx] => *promise_idx,
+            _ => env::promise_and(&yields),
+        };
+        Some(env::promise_then(
+            joint,
+            env::current_account_id(),
+            "on_execute_swaps",
+            vec![],
+            NearToken::from_yoctonear(0),
+            self.config.on_execute_swaps_gas,
+        ))
+    }
+
+    // NEP-141 receiver. Nothing is refunded through the token: the amounts the actions don't
+    // spend and the amount_in of failed swaps stay in the deposits of the sender
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) {
+        let token_in = env::predecessor_account_id();
+        self.assert_token_whitelisted(&sender_id, &token_in);
+        self.internal_register_deposit(&sender_id, &token_in);
+
+        match self.internal_process_token_transfer(sender_id, token_in, amount.0, msg) {
+            Some(promise_idx) => env::promise_return(promise_idx),
+            None => env::value_return(serde_json::to_vec(&U128(0)).unwrap()),
+        }
+    }
+
+    // Result of ft_on_transfer after the swaps of the message are settled
+    #[private]
+    pub fn on_execute_swaps() -> U128 {
+        U128(0)
     }
 
     pub fn get_swap_balances(&self, token_in: AccountId, token_out: AccountId) -> (U128, U128) {
         let pool_key = get_pool_key(&token_in, &token_out);
-        let pool = self.pools.get(&pool_key).expect("Pool not found");
+        let pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
         let (balance_in, balance_out) = if token_in == pool.token_a {
             (pool.token_a_balance, pool.token_b_balance)
         } else {
@@ -304,7 +840,8 @@ impl Contract {
         amount_out: Balance,
     ) -> Balance {
         let pool_key = get_pool_key(token_in, token_out);
-        let mut pool = self.pools.get(&pool_key).expect("Pool not found");
+        let mut pool = self.get_unlocked_pool(&pool_key);
+        self.internal_update_oracle(&pool_key, &mut pool);
 
         let (balance_in, balance_out) = if token_in == &pool.token_a {
             (pool.token_a_balance, pool.token_b_balance)
@@ -312,8 +849,8 @@ impl Contract {
             (pool.token_b_balance, pool.token_a_balance)
         };
 
-        let new_balance_in = balance_in + amount_in;
-        let new_balance_out = balance_out - amount_out;
+        let new_balance_in = math::add(balance_in, amount_in).unwrap_or_panic();
+        let new_balance_out = math::sub_reserve(balance_out, amount_out).unwrap_or_panic();
 
         if token_in == &pool.token_a {
             pool.token_a_balance = new_balance_in;
@@ -322,11 +859,38 @@ impl Contract {
             pool.token_b_balance = new_balance_in;
             pool.token_a_balance = new_balance_out;
         }
+        pool.assert_reserved_covered();
 
         self.pools.insert(&pool_key, &pool);
         amount_out
     }
 
+    // Errors internal_swap would panic with, checked before the fees of a settled swap are paid
+    fn check_swap(
+        &self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_in: Balance,
+        amount_out: Balance,
+    ) -> Result<(), ContractError> {
+        let pool_key = get_pool_key(token_in, token_out);
+        if self.is_pool_locked(&pool_key) {
+            return Err(ContractError::PoolLocked);
+        }
+        let pool = self
+            .pools
+            .get(&pool_key)
+            .ok_or(ContractError::PoolNotFound)?;
+
+        let (balance_in, balance_out) = pool.balances(token_in);
+        math::add(balance_in, amount_in)?;
+        // the output can't take the liquidity reserved for the other pending swaps
+        if math::sub_reserve(balance_out, amount_out)? <= pool.reserved(token_out) {
+            return Err(ContractError::ReservedLiquidity);
+        }
+        Ok(())
+    }
+
     fn internal_add_liquidity(
         &mut self,
         token_in: &AccountId,
@@ -334,29 +898,92 @@ impl Contract {
         amount_in: Balance,
         amount_other: Balance,
         sender_id: &AccountId,
-    ) {
+        min_shares: Balance,
+    ) -> Balance {
+        self.internal_try_add_liquidity(
+            token_in,
+            token_other,
+            amount_in,
+            amount_other,
+            sender_id,
+            min_shares,
+        )
+        .unwrap_or_panic()
+    }
+
+    // Add liquidity from the deposits of the sender, nothing is changed if it fails
+    fn internal_try_add_liquidity(
+        &mut self,
+        token_in: &AccountId,
+        token_other: &AccountId,
+        amount_in: Balance,
+        amount_other: Balance,
+        sender_id: &AccountId,
+        min_shares: Balance,
+    ) -> Result<Balance, ContractError> {
         let pool_key = get_pool_key(token_in, token_other);
-        let mut pool = self.pools.get(&pool_key).expect("Pool not found");
+        if self.is_pool_locked(&pool_key) {
+            return Err(ContractError::PoolLocked);
+        }
+        let mut pool = self
+            .pools
+            .get(&pool_key)
+            .ok_or(ContractError::PoolNotFound)?;
 
-        let (token_a_amount, token_b_amount) = if token_in == &pool.token_a {
+        let (max_a_amount, max_b_amount) = if token_in == &pool.token_a {
             (amount_in, amount_other)
         } else {
             (amount_other, amount_in)
         };
 
+        // Take only the amounts that match the current reserve ratio, the rest stays in deposits
+        let (token_a_amount, token_b_amount) =
+            pool.optimal_liquidity_amounts(max_a_amount, max_b_amount);
+
         let share = std::cmp::min(
-            token_a_amount * pool.total_shares / pool.token_a_balance,
-            token_b_amount * pool.total_shares / pool.token_b_balance,
+            math::shares_for_amount(token_a_amount, pool.token_a_balance, pool.total_shares)?,
+            math::shares_for_amount(token_b_amount, pool.token_b_balance, pool.total_shares)?,
         );
+        if share == 0 {
+            return Err(ContractError::LiquidityTooSmall);
+        }
+        if share < min_shares {
+            return Err(ContractError::SharesBelowMinimum {
+                shares: share,
+                min_shares,
+            });
+        }
+
+        let token_a = pool.token_a.clone();
+        let token_b = pool.token_b.clone();
+        for (token_id, amount) in [(&token_a, token_a_amount), (&token_b, token_b_amount)] {
+            let balance = self
+                .get_deposits(sender_id)
+                .tokens
+                .get(token_id)
+                .unwrap_or(0);
+            if balance < amount {
+                return Err(ContractError::InsufficientDeposit {
+                    token_id: token_id.clone(),
+                    balance,
+                    amount,
+                });
+            }
+        }
 
-        pool.token_a_balance += token_a_amount;
-        pool.token_b_balance += token_b_amount;
-        pool.total_shares += share;
+        self.internal_update_oracle(&pool_key, &mut pool);
+        self.internal_withdraw_deposit(sender_id, &token_a, token_a_amount);
+        self.internal_withdraw_deposit(sender_id, &token_b, token_b_amount);
+
+        pool.token_a_balance = math::add(pool.token_a_balance, token_a_amount).unwrap_or_panic();
+        pool.token_b_balance = math::add(pool.token_b_balance, token_b_amount).unwrap_or_panic();
+        pool.total_shares = math::add(pool.total_shares, share).unwrap_or_panic();
 
         let user_shares = pool.shares.get(sender_id).unwrap_or(0);
         pool.shares.insert(sender_id, &(user_shares + share));
 
         self.pools.insert(&pool_key, &pool);
+        Ok(share)
     }
 
     pub fn add_liquidity_from_deposits(
@@ -365,63 +992,143 @@ impl Contract {
         token_b: AccountId,
         amount_a: U128,
         amount_b: U128,
+        min_shares: Option<U128>,
     ) -> Balance {
         let sender_id = env::predecessor_account_id();
-        let deposits = self.get_deposits(&sender_id);
+        self.assert_deposit(&sender_id, &token_a, amount_a.0);
+        self.assert_deposit(&sender_id, &token_b, amount_b.0);
 
-        let balance_a = deposits.tokens.get(&token_a).unwrap_or(0);
-        let balance_b = deposits.tokens.get(&token_b).unwrap_or(0);
-
-        assert!(balance_a >= amount_a.0, "Insufficient balance of token_a");
-        assert!(balance_b >= amount_b.0, "Insufficient balance of token_b");
-
-        self.internal_add_liquidity(&token_a, &token_b, amount_a.0, amount_b.0, &sender_id);
+        self.internal_add_liquidity(
+            &token_a,
+            &token_b,
+            amount_a.0,
+            amount_b.0,
+            &sender_id,
+            min_shares.map(|shares| shares.0).unwrap_or(0),
+        );
 
         let pool_key = get_pool_key(&token_a, &token_b);
-        let pool = self.pools.get(&pool_key).unwrap();
+        let pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
         pool.shares.get(&sender_id).unwrap_or(0)
     }
-}
 
-fn get_pool_key(token_a: &AccountId, token_b: &AccountId) -> String {
-    let mut tokens = [token_a.to_string(), token_b.to_string()];
-    tokens.sort();
-    format!("{}:{}", tokens[0], tokens[1])
-}
+    // Burn shares and return the pool tokens to the deposits of the caller
+    pub fn remove_liquidity(
+        &mut self,
+        token_a: AccountId,
+        token_b: AccountId,
+        shares: U128,
+        min_amount_a: Option<U128>,
+        min_amount_b: Option<U128>,
+    ) -> (U128, U128) {
+        let sender_id = env::predecessor_account_id();
+        let (amount_a, amount_b) =
+            self.internal_remove_liquidity(&token_a, &token_b, shares.0, &sender_id);
 
-#[near_bindgen]
-impl FungibleTokenReceiver for Contract {
-    fn ft_on_transfer(
+        let min_amount_a = min_amount_a.map(|amount| amount.0).unwrap_or(0);
+        let min_amount_b = min_amount_b.map(|amount| amount.0).unwrap_or(0);
+        require(
+            amount_a >= min_amount_a,
+            ContractError::AmountBelowMinimum {
+                amount: amount_a,
+                min_amount: min_amount_a,
+            },
+        );
+        require(
+            amount_b >= min_amount_b,
+            ContractError::AmountBelowMinimum {
+                amount: amount_b,
+                min_amount: min_amount_b,
+            },
+        );
+
+        (U128(amount_a), U128(amount_b))
+    }
+
+    // Returns the removed amounts in the order of the given tokens
+    fn internal_remove_liquidity(
         &mut self,
-        sender_id: AccountId,
-        amount: U128,
-        msg: String,
-    ) -> PromiseOrValue<U128> {
-        let token_in = env::predecessor_account_id();
+        token_a: &AccountId,
+        token_b: &AccountId,
+        shares: Balance,
+        sender_id: &AccountId,
+    ) -> (Balance, Balance) {
+        let pool_key = get_pool_key(token_a, token_b);
+        let mut pool = self.get_unlocked_pool(&pool_key);
+        self.internal_update_oracle(&pool_key, &mut pool);
+
+        let user_shares = pool.shares.get(sender_id).unwrap_or(0);
+        require(shares > 0, ContractError::ZeroAmount);
+        require(user_shares >= shares, ContractError::InsufficientShares);
+
+        let (balance_a, balance_b) = pool.balances(token_a);
+        let amount_a =
+            math::amount_for_shares(shares, balance_a, pool.total_shares).unwrap_or_panic();
+        let amount_b =
+            math::amount_for_shares(shares, balance_b, pool.total_shares).unwrap_or_panic();
+
+        // the locked shares keep both reserves positive
+        let new_balance_a = math::sub_reserve(balance_a, amount_a).unwrap_or_panic();
+        let new_balance_b = math::sub_reserve(balance_b, amount_b).unwrap_or_panic();
+        if token_a == &pool.token_a {
+            pool.token_a_balance = new_balance_a;
+            pool.token_b_balance = new_balance_b;
+        } else {
+            pool.token_b_balance = new_balance_a;
+            pool.token_a_balance = new_balance_b;
+        }
+        // pending swaps can't be stranded by the removal
+        pool.assert_reserved_covered();
+        pool.total_shares -= shares;
+        pool.shares.insert(sender_id, &(user_shares - shares));
+        self.pools.insert(&pool_key, &pool);
 
-        self.internal_process_token_transfer(sender_id, token_in, amount.0, msg);
+        self.internal_deposit(sender_id, token_a, amount_a);
+        self.internal_deposit(sender_id, token_b, amount_b);
 
-        PromiseOrValue::Value(U128(0))
+        (amount_a, amount_b)
     }
 }
 
+// Take the amount of an action from what is left of the transferred tokens
+fn take_action_amount(amount_left: &mut Balance, amount: Option<U128>) -> Balance {
+    let amount = amount.map(|amount| amount.0).unwrap_or(*amount_left);
+    require(amount > 0, ContractError::ZeroActionAmount);
+    require(amount <= *amount_left, ContractError::ActionsOverspend);
+    *amount_left -= amount;
+    amount
+}
+
+fn get_pool_key(token_a: &AccountId, token_b: &AccountId) -> String {
+    let mut tokens = [token_a.to_string(), token_b.to_string()];
+    tokens.sort();
+    format!("{}:{}", tokens[0], tokens[1])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use near_sdk::IntoStorageKey;
     use std::str::FromStr;
 
+    // NEAR bob.near deposited for the storage of pending swaps
+    const STORAGE_BALANCE: Balance = 1_000_000_000_000_000_000_000_000;
+
     // Helper function to set up test context
     fn get_contract() -> (Contract, AccountId, AccountId, AccountId) {
-        let pools = UnorderedMap::new(StorageKey::Pools);
+        let pools = UnorderedMap::new(StorageKey::PoolsV1);
         let mut deposits: UnorderedMap<AccountId, AccountDeposits> =
-            UnorderedMap::new(StorageKey::Deposits);
+            UnorderedMap::new(StorageKey::DepositsV1);
 
         let account_a: AccountId = AccountId::from_str("bob.near").unwrap();
         let account_b: AccountId = AccountId::from_str("token_in.near").unwrap();
         let account_c: AccountId = AccountId::from_str("token_out.near").unwrap();
 
         let mut account_a_tokens: UnorderedMap<AccountId, Balance> =
-            UnorderedMap::new(StorageKey::TokenDeposits {
+            UnorderedMap::new(StorageKey::TokenDepositsV1 {
                 account_id: account_a.clone(),
             });
         account_a_tokens.insert(&account_b, &1_000_000);
@@ -432,12 +1139,46 @@ mod tests {
                 tokens: account_a_tokens,
             },
         );
+        let mut storage_balances = LookupMap::new(StorageKey::StorageBalances);
+        storage_balances.insert(
+            &account_a,
+            &StorageBalance {
+                total: STORAGE_BALANCE,
+                locked: 0,
+            },
+        );
 
         let contract = Contract {
             agent: "test-agent".to_string(),
             agent_account_id: AccountId::from_str("agent.near").unwrap(),
             pools,
             deposits,
+            agent_public_key: None,
+            quote_nonces: LookupMap::new(StorageKey::QuoteNonces),
+            next_request_id: 0,
+            pending_swaps: LookupMap::new(StorageKey::PendingSwaps),
+            pending_data_ids: LookupMap::new(StorageKey::PendingDataIds),
+            batch_window_blocks: 0,
+            swap_batches: LookupMap::new(StorageKey::SwapBatches),
+            open_swap_batches: LookupMap::new(StorageKey::OpenSwapBatches),
+            pool_creation_fee: None,
+            referral_fee: 0,
+            reserve_expected_output: false,
+            whitelisted_tokens: UnorderedSet::new(StorageKey::WhitelistedTokens),
+            user_whitelisted_tokens: LookupMap::new(StorageKey::UserWhitelists),
+            token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+            observations: LookupMap::new(StorageKey::Observations),
+            flash_swaps: LookupMap::new(StorageKey::FlashSwaps),
+            flash_debts: LookupMap::new(StorageKey::FlashDebts),
+            agent_stats: LookupMap::new(StorageKey::AgentStats),
+            agent_bonds: LookupMap::new(StorageKey::AgentBonds),
+            unbonding_period_sec: staking::DEFAULT_UNBONDING_PERIOD_SEC,
+            near_slash_penalty: 0,
+            config: Config::default(),
+            user_pending_swaps: LookupMap::new(StorageKey::UserPendingSwaps),
+            storage_balances,
+            pools_v0: UnorderedMap::new(StorageKey::PoolsV0),
+            deposits_v0: UnorderedMap::new(StorageKey::DepositsV0),
         };
 
         (contract, account_a, account_b, account_c)
@@ -458,6 +1199,7 @@ mod tests {
             U128(1_000_000),
             account_c.clone(),
             U128(1_000_000),
+            None,
         );
 
         let pool_info = contract.get_pool_info(account_b, account_c);
@@ -465,14 +1207,28 @@ mod tests {
         let (token_a_balance, token_b_balance, total_shares) = pool_info.unwrap();
         assert_eq!(token_a_balance, 1_000_000);
         assert_eq!(token_b_balance, 1_000_000);
-        assert_eq!(total_shares, INIT_SHARES_SUPPLY);
+        assert_eq!(total_shares, 1_000_000);
     }
 
     #[test]
-    #[should_panic(expected = "Need to deposit tokens A")]
+    #[should_panic(expected = "E201: Need to deposit tokens A")]
     fn test_create_pool_small_deposit() {
         let (mut contract, _, account_b, account_c) = get_contract();
-        contract.create_pool(account_b, U128(2_000_000), account_c, U128(1_000_000));
+        contract.create_pool(account_b, U128(2_000_000), account_c, U128(1_000_000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "E101: Pool not found")]
+    fn test_get_shares_missing_pool() {
+        let (contract, account_a, account_b, account_c) = get_contract();
+        contract.get_shares(account_b, account_c, account_a);
+    }
+
+    #[test]
+    fn test_get_user_deposits_unknown_account() {
+        let (contract, _, _, _) = get_contract();
+        let account_id = AccountId::from_str("alice.near").unwrap();
+        assert!(contract.get_user_deposits(account_id).is_empty());
     }
 
     #[test]
@@ -483,6 +1239,7 @@ mod tests {
             U128(1_000_000),
             account_c.clone(),
             U128(1_000_000),
+            None,
         );
 
         let pool_info = contract.get_pool_info(account_b.clone(), account_c.clone());
@@ -501,10 +1258,11 @@ mod tests {
             U128(1_000_000),
             account_c.clone(),
             U128(1_000_000),
+            None,
         );
 
         let shares = contract.get_shares(account_b, account_c, account_a);
-        assert_eq!(shares, INIT_SHARES_SUPPLY);
+        assert_eq!(shares, 1_000_000 - MIN_LOCKED_SHARES);
     }
 
     #[test]
@@ -527,6 +1285,7 @@ mod tests {
             U128(1_000_000),
             account_c.clone(),
             U128(1_000_000),
+            None,
         );
 
         // Test swap
@@ -538,7 +1297,2965 @@ mod tests {
         assert!(amount_out > 0);
 
         let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
-        assert_eq!(pool_info.0, 1000_500); // token_a_balance
+        assert_eq!(pool_info.0, 1_000_500); // token_a_balance
         assert_eq!(pool_info.1, 999_500); // token_b_balance
     }
+
+    fn swap_request(
+        sender_id: AccountId,
+        token_in: AccountId,
+        token_out: AccountId,
+        amount_in: Balance,
+        min_amount_out: Balance,
+    ) -> SwapRequest {
+        SwapRequest {
+            sender_id,
+            token_in,
+            token_out,
+            amount_in: U128(amount_in),
+            min_amount_out: U128(min_amount_out),
+            receiver_id: None,
+            msg: None,
+            referral_id: None,
+            agent_payment: None,
+            zap: None,
+        }
+    }
+
+    fn signed_quote(
+        contract: &mut Contract,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        nonce: u64,
+    ) -> (SwapQuote, Base64VecU8) {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = PublicKey::from_parts(
+            near_sdk::CurveType::ED25519,
+            signing_key.verifying_key().as_bytes().to_vec(),
+        )
+        .unwrap();
+        contract.set_agent_public_key(public_key);
+
+        let quote = SwapQuote {
+            sender_id: sender_id.clone(),
+            token_in: token_in.clone(),
+            token_out: token_out.clone(),
+            amount_in: U128(1_000),
+            amount_out: U128(990),
+            nonce: near_sdk::json_types::U64(nonce),
+            expires_at: near_sdk::json_types::U64(u64::MAX),
+        };
+        let signature = signing_key.sign(&quote.message()).to_bytes().to_vec();
+
+        (quote, Base64VecU8(signature))
+    }
+
+    #[test]
+    fn test_swap_with_quote() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        let (quote, signature) = signed_quote(&mut contract, &account_a, &account_b, &account_c, 1);
+        let amount_out =
+            contract.internal_swap_with_quote(&account_a, &account_b, 1_000, quote, signature);
+
+        assert_eq!(amount_out, 990);
+        assert_eq!(contract.get_quote_nonce(account_a).0, 1);
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_001_000);
+        assert_eq!(pool_info.1, 999_010);
+    }
+
+    #[test]
+    #[should_panic(expected = "E509: Quote nonce already used")]
+    fn test_swap_with_quote_replay() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+        let (quote, signature) = signed_quote(&mut contract, &account_a, &account_b, &account_c, 1);
+        contract.internal_swap_with_quote(
+            &account_a,
+            &account_b,
+            1_000,
+            quote.clone(),
+            signature.clone(),
+        );
+        contract.internal_swap_with_quote(&account_a, &account_b, 1_000, quote, signature);
+    }
+
+    #[test]
+    #[should_panic(expected = "E510: Invalid quote signature")]
+    fn test_swap_with_quote_tampered() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        let (mut quote, signature) =
+            signed_quote(&mut contract, &account_a, &account_b, &account_c, 1);
+        quote.amount_out = U128(2_000);
+        contract.internal_swap_with_quote(&account_a, &account_b, 1_000, quote, signature);
+    }
+
+    #[test]
+    fn test_swap_batch() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_batch_window(10);
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        contract.internal_deposit(&account_a, &account_c, 2_000);
+
+        let result = contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+        assert!(matches!(result, SwapResult::Pending(_)));
+        // the second swap joins the batch without a yield of its own
+        let result = contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_c.clone(),
+            account_b.clone(),
+            2_000,
+            0,
+        ));
+        assert!(matches!(result, SwapResult::Queued));
+
+        let batch = contract.get_swap_batch(0).unwrap();
+        assert_eq!(batch.pool_id, get_pool_key(&account_b, &account_c));
+        assert_eq!(batch.request_ids, vec![0, 1]);
+        assert_eq!(
+            contract.pending_swaps.get(&1).unwrap().data_id,
+            batch.data_id
+        );
+
+        // the agent settles the queued swap in its own transaction
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(contract.agent_account_id.clone())
+            .build());
+        assert!(contract
+            .agent_response_batch(vec![(1, U128(1_990))])
+            .is_empty());
+        assert!(contract.pending_swaps.get(&1).is_none());
+        assert_eq!(contract.get_swap_batch(0).unwrap().request_ids, vec![0]);
+
+        // the yield of the first swap closes the batch
+        contract.on_agent_market_maker_response(0, Ok(U128(999)));
+        assert!(contract.get_swap_batch(0).is_none());
+        assert!(contract.open_swap_batches.get(&batch.pool_id).is_none());
+    }
+
+    #[test]
+    fn test_swap_batch_refunds_queued_swaps() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_batch_window(10);
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+        let deposit = contract.get_deposits(&account_a).tokens.get(&account_b);
+
+        for _ in 0..2 {
+            contract.run_agent_market_maker(swap_request(
+                account_a.clone(),
+                account_b.clone(),
+                account_c.clone(),
+                1_000,
+                0,
+            ));
+        }
+
+        // the queued swap the agent didn't resolve goes back to deposits with the first one
+        contract.on_agent_market_maker_response(0, Err(PromiseError::Failed));
+        assert!(contract.pending_swaps.get(&1).is_none());
+        assert!(contract.get_swap_batch(0).is_none());
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            deposit
+        );
+        assert_eq!(contract.get_user_pending_swaps(account_a), 0);
+    }
+
+    #[test]
+    fn test_swap_batch_uniform() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            Some(30),
+        );
+        contract.set_batch_window(10);
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        contract.internal_deposit(&account_a, &account_c, 2_000);
+
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_c.clone(),
+            account_b.clone(),
+            2_000,
+            0,
+        ));
+        let pool_key = get_pool_key(&account_b, &account_c);
+        let data_id = contract.get_swap_batch(0).unwrap().data_id;
+        let (balance_b, _) = contract.pools.get(&pool_key).unwrap().balances(&account_b);
+
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(contract.agent_account_id.clone())
+            .build());
+        // a batch that isn't pending is returned as failed
+        let failed = contract.agent_response_batch_uniform(
+            vec![data_id, [7; 32]],
+            ClearingPrice {
+                token_a_amount: U128(1),
+                token_b_amount: U128(1),
+            },
+        );
+        assert!(failed.contains(&[7; 32]));
+
+        // the queued swap settles at the clearing price of its amount_in after the 0.3% fee
+        assert!(contract.pending_swaps.get(&1).is_none());
+        assert_eq!(
+            contract
+                .pools
+                .get(&pool_key)
+                .unwrap()
+                .balances(&account_b)
+                .0,
+            balance_b - 1_994
+        );
+    }
+
+    #[test]
+    fn test_agent_response_batch_failed() {
+        let (mut contract, _, _, _) = get_contract();
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(contract.agent_account_id.clone())
+            .build());
+
+        // a swap that isn't waiting for the agent can't be resolved
+        let failed = contract.agent_response_batch(vec![(7, U128(1_000))]);
+        assert_eq!(failed, vec![7]);
+    }
+
+    #[test]
+    fn test_clearing_price() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool = contract
+            .pools
+            .get(&get_pool_key(&account_b, &account_c))
+            .unwrap();
+        let price = ClearingPrice {
+            token_a_amount: U128(2),
+            token_b_amount: U128(3),
+        };
+
+        assert_eq!(price.amount_out(&pool, &account_b, 1_000), 1_500);
+        assert_eq!(price.amount_out(&pool, &account_c, 1_000), 666);
+    }
+
+    #[test]
+    fn test_execute_multiple_swaps() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        let msg = json!({ "Execute": { "actions": [
+            { "Swap": { "token_out": account_c, "min_amount_out": "0", "amount": "400" } },
+            { "Swap": { "token_out": account_c, "min_amount_out": "0", "amount": "500" } },
+        ]}});
+        let promise_idx = contract.internal_process_token_transfer(
+            account_a.clone(),
+            account_b.clone(),
+            1_000,
+            msg.to_string(),
+        );
+        assert!(promise_idx.is_some());
+
+        // the transfer resolves after both yields
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let callback = receipts.last().unwrap();
+        assert_eq!(callback.receipt_indices.len(), 2);
+        match &callback.actions[0] {
+            near_sdk::mock::MockAction::FunctionCallWeight { method_name, .. } => {
+                assert_eq!(method_name, b"on_execute_swaps")
+            }
+            action => panic!("Unexpected action {:?}", action),
+        }
+
+        assert_eq!(
+            contract.pending_swaps.get(&0).unwrap().request.amount_in.0,
+            400
+        );
+        assert_eq!(
+            contract.pending_swaps.get(&1).unwrap().request.amount_in.0,
+            500
+        );
+        // the rest of the transfer stays in deposits
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Actions spend more than the transferred amount")]
+    fn test_execute_overspend() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        let msg = json!({ "Execute": { "actions": [
+            { "Swap": { "token_out": account_c, "min_amount_out": "0", "amount": "800" } },
+            { "Swap": { "token_out": account_c, "min_amount_out": "0", "amount": "800" } },
+        ]}});
+        contract.internal_process_token_transfer(account_a, account_b, 1_000, msg.to_string());
+    }
+
+    #[test]
+    fn test_agent_response_below_minimum() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            990,
+        ));
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(0)
+        );
+
+        // amount_in goes back to deposits and the pool is untouched
+        assert!(contract
+            .on_agent_market_maker_response(0, Ok(U128(900)))
+            .is_none());
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(1_000)
+        );
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_000_000);
+    }
+
+    #[test]
+    fn test_agent_response_insufficient_liquidity() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+
+        // the pool can't pay the output, the swap is refunded instead of failing the callback
+        assert!(contract
+            .on_agent_market_maker_response(0, Ok(U128(1_000_000)))
+            .is_none());
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(1_000)
+        );
+        assert_eq!(contract.get_user_pending_swaps(account_a), 0);
+        assert!(contract.pending_swaps.get(&0).is_none());
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_000_000);
+        assert_eq!(pool_info.1, 1_000_000);
+    }
+
+    #[test]
+    fn test_agent_response_reserved_liquidity() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            600_000,
+        ));
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            300_000,
+        ));
+
+        // 700_000 would take the 300_000 reserved for the second swap, the first one is refunded
+        assert!(contract
+            .on_agent_market_maker_response(0, Ok(U128(700_000)))
+            .is_none());
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(1_000)
+        );
+        assert_eq!(contract.get_user_pending_swaps(account_a), 1);
+        assert!(contract.pending_swaps.get(&0).is_none());
+        // the refunded response counts as rejected
+        assert_eq!(contract.get_agent_stats(None).rejected, 1);
+        let pool = contract.get_pool(account_b, account_c).unwrap();
+        assert_eq!(
+            (pool.token_a_balance.0, pool.token_b_balance.0),
+            (1_000_000, 1_000_000)
+        );
+        assert_eq!(pool.reserved_b.0, 300_000);
+    }
+
+    #[test]
+    fn test_pending_swap_storage_deposit() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        // the pool creator pays for the storage of the pool
+        let available = contract.storage_balance_of(account_a.clone()).available.0;
+        assert!(available < STORAGE_BALANCE);
+
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+        let storage_cost = contract.pending_swaps.get(&0).unwrap().storage_cost;
+        assert!(storage_cost > 0);
+        let balance = contract.storage_balance_of(account_a.clone());
+        assert_eq!(balance.total.0, STORAGE_BALANCE);
+        assert_eq!(balance.available.0, available - storage_cost);
+
+        // the storage deposit is released when the swap is settled
+        contract.on_agent_market_maker_response(0, Ok(U128(990)));
+        let balance = contract.storage_balance_of(account_a);
+        assert_eq!(balance.available.0, available);
+    }
+
+    #[test]
+    #[should_panic(expected = "E205: Storage deposit of")]
+    fn test_pending_swap_without_storage_deposit() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let account_d = AccountId::from_str("alice.near").unwrap();
+        contract.internal_deposit(&account_d, &account_b, 1_000);
+
+        contract.run_agent_market_maker(swap_request(account_d, account_b, account_c, 1_000, 0));
+    }
+
+    #[test]
+    fn test_add_liquidity_balanced() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(500_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 100_000);
+        contract.internal_deposit(&account_a, &account_c, 100_000);
+
+        contract.add_liquidity_from_deposits(
+            account_b.clone(),
+            account_c.clone(),
+            U128(100_000),
+            U128(100_000),
+            None,
+        );
+
+        // only 50_000 of token C match the 2:1 ratio, the rest stays in deposits.
+        // sqrt(1_000_000 * 500_000) = 707_106 initial shares grow by a tenth, rounded down
+        let pool_info = contract
+            .get_pool_info(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool_info.0, 1_100_000);
+        assert_eq!(pool_info.1, 550_000);
+        assert_eq!(pool_info.2, 707_106 + 70_710);
+
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 0);
+        assert_eq!(deposits.tokens.get(&account_c).unwrap(), 550_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "are less than min_shares")]
+    fn test_add_liquidity_min_shares() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(500_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 100_000);
+        contract.internal_deposit(&account_a, &account_c, 100_000);
+
+        contract.add_liquidity_from_deposits(
+            account_b,
+            account_c,
+            U128(100_000),
+            U128(100_000),
+            Some(U128(707_106)),
+        );
+    }
+
+    #[test]
+    fn test_remove_liquidity() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(500_000),
+            None,
+        );
+
+        let (amount_b, amount_c) = contract.remove_liquidity(
+            account_c.clone(),
+            account_b.clone(),
+            U128(707_106 / 2),
+            None,
+            None,
+        );
+        assert_eq!(amount_b.0, 250_000);
+        assert_eq!(amount_c.0, 500_000);
+
+        let pool_info = contract
+            .get_pool_info(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool_info.0, 500_000);
+        assert_eq!(pool_info.1, 250_000);
+        assert_eq!(pool_info.2, 707_106 - 707_106 / 2);
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 500_000);
+        assert_eq!(deposits.tokens.get(&account_c).unwrap(), 750_000);
+    }
+
+    #[test]
+    fn test_zap_in() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::OnChainOnly,
+        );
+        let msg = json!({ "Execute": { "actions": [
+            { "ZapIn": { "token_other": account_c, "min_shares": "1" } },
+        ]}});
+        contract.internal_process_token_transfer(
+            account_a.clone(),
+            account_b.clone(),
+            210_000,
+            msg.to_string(),
+        );
+
+        // sqrt(1_000_000 * 1_210_000) - 1_000_000 = 100_000 swapped for 90_909 of token C
+        let pool_info = contract
+            .get_pool_info(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool_info.0, 1_210_000);
+        assert_eq!(pool_info.1, 1_000_000);
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 0);
+        assert!(deposits.tokens.get(&account_c).unwrap() <= 1);
+        assert!(contract.get_shares(account_b, account_c, account_a) > 1_000_000);
+    }
+
+    #[test]
+    fn test_zap_out() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::OnChainOnly,
+        );
+
+        contract.zap_out(
+            account_b.clone(),
+            account_c.clone(),
+            U128(100_000),
+            account_b.clone(),
+            U128(180_000),
+        );
+
+        // 100_000 of token B plus 100_000 of token C swapped at 900_000:900_000
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 190_000);
+        assert_eq!(deposits.tokens.get(&account_c).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_zap_in_agent() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let msg = json!({ "Execute": { "actions": [
+            { "ZapIn": { "token_other": account_c, "min_shares": "1" } },
+        ]}});
+        contract.internal_process_token_transfer(
+            account_a.clone(),
+            account_b.clone(),
+            210_000,
+            msg.to_string(),
+        );
+
+        // the swap leg of 100_000 waits for the agent, the rest stays in deposits meanwhile
+        let request = contract.pending_swaps.get(&0).unwrap().request;
+        assert_eq!(request.amount_in.0, 100_000);
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 110_000);
+
+        contract.on_agent_market_maker_response(0, Ok(U128(90_909)));
+        let pool_info = contract
+            .get_pool_info(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool_info.0, 1_210_000);
+        assert_eq!(pool_info.1, 1_000_000);
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 0);
+        assert!(deposits.tokens.get(&account_c).unwrap() <= 1);
+        assert!(contract.get_shares(account_b, account_c, account_a) > 1_000_000);
+    }
+
+    #[test]
+    fn test_zap_in_creator_fee() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            Some(100),
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::OnChainOnly,
+        );
+        let account_d = AccountId::from_str("alice.near").unwrap();
+        let msg = json!({ "Execute": { "actions": [
+            { "ZapIn": { "token_other": account_c, "min_shares": "1" } },
+        ]}});
+        contract.internal_process_token_transfer(
+            account_d.clone(),
+            account_b.clone(),
+            210_000,
+            msg.to_string(),
+        );
+
+        // the swapped part makes up for the 1% creator fee, both sides are added up to rounding
+        let deposits = contract.get_deposits(&account_d);
+        assert!(deposits.tokens.get(&account_b).unwrap() <= 1);
+        assert!(deposits.tokens.get(&account_c).unwrap() <= 1);
+        assert!(contract.get_shares(account_b, account_c, account_d) > 0);
+    }
+
+    #[test]
+    fn test_zap_out_creator_fee() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            Some(100),
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::OnChainOnly,
+        );
+
+        contract.zap_out(
+            account_b.clone(),
+            account_c.clone(),
+            U128(100_000),
+            account_b.clone(),
+            U128(180_000),
+        );
+
+        // 1% of the 100_000 of token C goes to the creator, 99_000 swapped at 900_000:900_000
+        let deposits = contract.get_deposits(&account_a);
+        assert_eq!(deposits.tokens.get(&account_b).unwrap(), 189_189);
+        // the caller is also the creator
+        assert_eq!(deposits.tokens.get(&account_c).unwrap(), 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Initial reserves must be at least")]
+    fn test_create_pool_small_reserves() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(account_b, U128(1_000_000), account_c, U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient liquidity")]
+    fn test_internal_swap_drain() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        contract.internal_swap(&account_b, &account_c, 1, 1_000_000);
+    }
+
+    #[test]
+    fn test_remove_all_liquidity() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        // the creator can't burn the locked shares, each of them keeps one unit of both reserves
+        let shares = contract.get_shares(account_b.clone(), account_c.clone(), account_a);
+        contract.remove_liquidity(
+            account_b.clone(),
+            account_c.clone(),
+            U128(shares),
+            None,
+            None,
+        );
+
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_000);
+        assert_eq!(pool_info.1, 1_000);
+        assert_eq!(pool_info.2, MIN_LOCKED_SHARES);
+    }
+
+    #[test]
+    #[should_panic(expected = "E105: Token bob.near is not whitelisted")]
+    fn test_ft_on_transfer_not_whitelisted() {
+        let (mut contract, account_a, _, _) = get_contract();
+        let msg = json!({ "Execute": { "actions": [{ "Deposit": {} }] } });
+        contract.ft_on_transfer(account_a, U128(1_000), msg.to_string());
+    }
+
+    #[test]
+    fn test_ft_on_transfer_storage_deposit() {
+        let (mut contract, _, account_b, _) = get_contract();
+        let account_d = AccountId::from_str("alice.near").unwrap();
+        contract.add_whitelisted_tokens(vec![account_b.clone()]);
+        contract.storage_balances.insert(
+            &account_d,
+            &StorageBalance {
+                total: STORAGE_BALANCE,
+                locked: 0,
+            },
+        );
+
+        // the first deposit of a token locks the storage of its entry
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(account_b.clone())
+            .build());
+        let msg = json!({ "Execute": { "actions": [{ "Deposit": {} }] } });
+        contract.ft_on_transfer(account_d.clone(), U128(1_000), msg.to_string());
+        let available = contract.storage_balance_of(account_d.clone()).available.0;
+        assert!(available < STORAGE_BALANCE);
+
+        contract.ft_on_transfer(account_d.clone(), U128(1_000), msg.to_string());
+        assert_eq!(
+            contract.storage_balance_of(account_d.clone()).available.0,
+            available
+        );
+        assert_eq!(
+            contract.get_user_deposits(account_d),
+            vec![(account_b, U128(2_000))]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "E205: Storage deposit of")]
+    fn test_ft_on_transfer_without_storage_deposit() {
+        let (mut contract, _, account_b, _) = get_contract();
+        let account_d = AccountId::from_str("alice.near").unwrap();
+        contract.add_whitelisted_tokens(vec![account_b.clone()]);
+
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(account_b)
+            .build());
+        let msg = json!({ "Execute": { "actions": [{ "Deposit": {} }] } });
+        contract.ft_on_transfer(account_d, U128(1_000), msg.to_string());
+    }
+
+    #[test]
+    fn test_token_whitelist() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        let account_d = AccountId::from_str("alice.near").unwrap();
+
+        contract.add_whitelisted_tokens(vec![account_b.clone()]);
+        assert!(contract.is_token_whitelisted(&account_d, &account_b));
+        assert!(!contract.is_token_whitelisted(&account_a, &account_c));
+
+        // the registration is paid from the attached deposit
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(account_a.clone())
+            .attached_deposit(NearToken::from_near(1))
+            .build());
+        contract.register_tokens(vec![account_c.clone()]);
+        assert!(contract.is_token_whitelisted(&account_a, &account_c));
+        assert!(!contract.is_token_whitelisted(&account_d, &account_c));
+        assert_eq!(
+            contract.get_user_whitelisted_tokens(account_a),
+            vec![account_c]
+        );
+
+        contract.remove_whitelisted_tokens(vec![account_b.clone()]);
+        assert!(!contract.is_token_whitelisted(&account_d, &account_b));
+    }
+
+    #[test]
+    #[should_panic(expected = "E205: Storage deposit of")]
+    fn test_register_tokens_without_deposit() {
+        let (mut contract, _, _, account_c) = get_contract();
+        contract.register_tokens(vec![account_c]);
+    }
+
+    #[test]
+    fn test_token_metadata_cache() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        let metadata = FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "Token In".to_string(),
+            symbol: "TIN".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 18,
+        };
+        let account_a = AccountId::from_str("bob.near").unwrap();
+
+        // the deposit doesn't cover the storage, nothing is cached
+        assert!(contract
+            .on_ft_metadata(
+                account_b.clone(),
+                account_a.clone(),
+                U128(1),
+                Ok(metadata.clone())
+            )
+            .is_none());
+        assert!(contract.get_token_metadata(account_b.clone()).is_none());
+
+        contract.on_ft_metadata(
+            account_b.clone(),
+            account_a,
+            U128(NearToken::from_near(1).as_yoctonear()),
+            Ok(metadata),
+        );
+
+        let cached = contract.get_token_metadata(account_b.clone()).unwrap();
+        assert_eq!(cached.symbol, "TIN");
+        assert_eq!(cached.decimals, 18);
+        assert_eq!(
+            contract.tokens_metadata_json(&account_b, &account_c),
+            json!({
+                "token_in.near": { "symbol": "TIN", "decimals": 18 },
+                "token_out.near": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_pool_creator_fee() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            Some(50),
+        );
+        let pool = contract
+            .get_pool(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool.creator_id, account_a);
+        assert_eq!(pool.creator_fee, 50);
+        assert_eq!(contract.get_pools(0, 10).len(), 1);
+
+        // 0.5% of amount_in goes to the creator deposits, the rest to the pool
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        let (quote, signature) = signed_quote(&mut contract, &account_a, &account_b, &account_c, 1);
+        contract.internal_swap_with_quote(&account_a, &account_b, 1_000, quote, signature);
+
+        let pool_info = contract
+            .get_pool_info(account_b.clone(), account_c)
+            .unwrap();
+        assert_eq!(pool_info.0, 1_000_995);
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(5)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "E107: Creator fee can't be more than 100")]
+    fn test_pool_creator_fee_too_high() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b,
+            U128(1_000_000),
+            account_c,
+            U128(1_000_000),
+            Some(101),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "E106: Pool creation fee of 1 yoctoNEAR is not paid")]
+    fn test_pool_creation_fee_near() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.set_pool_creation_fee(Some(PoolCreationFee::Near { amount: U128(1) }));
+        contract.create_pool(account_b, U128(1_000_000), account_c, U128(1_000_000), None);
+    }
+
+    #[test]
+    fn test_pool_creation_fee_token() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.set_pool_creation_fee(Some(PoolCreationFee::Token {
+            token_id: account_b.clone(),
+            amount: U128(1_000),
+        }));
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c,
+            U128(1_000_000),
+            None,
+        );
+
+        let fees = contract.get_deposits(&env::current_account_id());
+        assert_eq!(fees.tokens.get(&account_b), Some(1_000));
+    }
+
+    fn set_block_timestamp_sec(timestamp: u64) {
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .block_timestamp(timestamp * 1_000_000_000)
+            .build());
+    }
+
+    #[test]
+    fn test_twap() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        set_block_timestamp_sec(100);
+        contract.internal_swap(&account_b, &account_c, 1_000_000, 500_000);
+        assert_eq!(contract.get_observations(pool_id.clone()).len(), 2);
+
+        // price_a is 1.0 for the first 100 seconds and 0.25 for the next 100 seconds
+        set_block_timestamp_sec(200);
+        let twap = contract.get_twap(pool_id.clone(), 200);
+        assert_eq!(twap.token_a, account_b);
+        assert_eq!(twap.price_a, U256::from(oracle::PRICE_PRECISION * 5 / 8));
+        assert_eq!(twap.price_b, U256::from(oracle::PRICE_PRECISION * 5 / 2));
+
+        let twap = contract.get_twap(pool_id, 100);
+        assert_eq!(twap.price_a, U256::from(oracle::PRICE_PRECISION / 4));
+        assert_eq!(twap.price_b, U256::from(oracle::PRICE_PRECISION * 4));
+    }
+
+    #[test]
+    fn test_twap_interpolated() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        set_block_timestamp_sec(100);
+        contract.internal_swap(&account_b, &account_c, 1_000_000, 500_000);
+
+        // the window starts at 50 between the observations at 0 and 100, price_a is 1.0 for
+        // 50 seconds and 0.25 for 100 seconds
+        set_block_timestamp_sec(200);
+        let twap = contract.get_twap(pool_id, 150);
+        assert_eq!(twap.price_a, U256::from(oracle::PRICE_PRECISION / 2));
+        assert_eq!(twap.price_b, U256::from(oracle::PRICE_PRECISION * 3));
+    }
+
+    #[test]
+    fn test_oracle_storage() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        let initial_storage_usage = env::storage_usage();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let locked = STORAGE_BALANCE - contract.storage_balance_of(account_a).available.0;
+
+        // filling the ring buffer and wrapping around stays within what the creator paid
+        let pool_id = get_pool_key(&account_b, &account_c);
+        for timestamp in 1..=oracle::OBSERVATIONS_CAPACITY as u64 + 10 {
+            set_block_timestamp_sec(timestamp);
+            let mut pool = contract.pools.get(&pool_id).unwrap();
+            contract.internal_update_oracle(&pool_id, &mut pool);
+            contract.pools.insert(&pool_id, &pool);
+        }
+        let observations = contract.get_observations(pool_id);
+        assert_eq!(observations.len(), oracle::OBSERVATIONS_CAPACITY as usize);
+        assert_eq!(observations[0].timestamp, 11);
+        assert!(storage::storage_cost(initial_storage_usage) <= locked);
+    }
+
+    #[test]
+    fn test_twap_imbalanced_pool() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.internal_deposit(&account_a, &account_c, u128::MAX / 2);
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000),
+            account_c.clone(),
+            U128(u128::MAX / 2),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        // price_a doesn't fit in u128, it's averaged without saturating
+        set_block_timestamp_sec(100);
+        let twap = contract.get_twap(pool_id, 100);
+        assert_eq!(
+            twap.price_a,
+            U256::from(u128::MAX / 2) * U256::from(oracle::PRICE_PRECISION) / 1_000
+        );
+        assert_eq!(twap.price_b, U256::zero());
+    }
+
+    #[test]
+    #[should_panic(expected = "E109: Not enough price observations for the TWAP window")]
+    fn test_twap_window_too_long() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        set_block_timestamp_sec(100);
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        set_block_timestamp_sec(200);
+        contract.get_twap(get_pool_key(&account_b, &account_c), 150);
+    }
+
+    #[test]
+    fn test_swap_to_receiver() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        let receiver_id = AccountId::from_str("vault.near").unwrap();
+
+        contract.swap(
+            account_b.clone(),
+            account_c,
+            U128(1_000),
+            U128(0),
+            Some(receiver_id.clone()),
+            Some("deposit".to_string()),
+        );
+
+        let request = contract.pending_swaps.get(&0).unwrap().request;
+        assert_eq!(request.sender_id, account_a);
+        assert_eq!(request.receiver_id, Some(receiver_id));
+        assert_eq!(request.msg, Some("deposit".to_string()));
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_send_tokens_call_refund() {
+        let (mut contract, account_a, _, account_c) = get_contract();
+
+        // the receiver used 400 of 1_000, the rest goes back to the sender deposits
+        contract.on_send_tokens_call(
+            account_a.clone(),
+            account_c.clone(),
+            U128(1_000),
+            Ok(U128(400)),
+        );
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_c),
+            Some(1_000_600)
+        );
+    }
+
+    #[test]
+    fn test_referral_fee() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_referral_fee(50);
+        let referral_id = AccountId::from_str("referral.near").unwrap();
+
+        let msg = json!({ "Execute": { "actions": [
+            { "Swap": { "token_out": account_c, "min_amount_out": "900", "referral_id": referral_id } },
+        ]}});
+        contract.internal_process_token_transfer(
+            account_a.clone(),
+            account_b.clone(),
+            1_000,
+            msg.to_string(),
+        );
+        assert_eq!(
+            contract.pending_swaps.get(&0).unwrap().request.referral_id,
+            Some(referral_id.clone())
+        );
+
+        // 0.5% of amount_in goes to the referrer deposits, the rest to the pool
+        assert_eq!(
+            contract.on_agent_market_maker_response(0, Ok(U128(990))),
+            Some(U128(990))
+        );
+        assert_eq!(
+            contract.get_deposits(&referral_id).tokens.get(&account_b),
+            Some(5)
+        );
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_000_995);
+        assert_eq!(pool_info.1, 999_010);
+    }
+
+    #[test]
+    #[should_panic(expected = "E407: Referral fee can't be more than 100")]
+    fn test_referral_fee_too_high() {
+        let (mut contract, _, _, _) = get_contract();
+        contract.set_referral_fee(101);
+    }
+
+    #[test]
+    fn test_flash_swap() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        // only the 0.3% fee of the 1_002 that buys 1_000 at the pool price is taken up front
+        contract.internal_deposit(&account_a, &account_b, 4);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                None,
+                "".to_string(),
+            )
+            .detach();
+        assert!(contract.is_pool_locked(&pool_id));
+        assert_eq!(
+            contract.get_flash_swap(pool_id.clone()).unwrap().fee,
+            U128(4)
+        );
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(0)
+        );
+
+        // the borrower deposits the 1_002 before the transfer resolves
+        contract.internal_deposit(&account_a, &account_b, 1_002);
+        contract.on_flash_swap(pool_id.clone(), Ok(U128(1_000)));
+
+        assert!(!contract.is_pool_locked(&pool_id));
+        assert!(contract.get_flash_debt(account_a.clone()).is_none());
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_001_006);
+        assert_eq!(pool_info.1, 999_000);
+    }
+
+    #[test]
+    fn test_flash_swap_partly_used() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        // flash loan repaid in the borrowed token, the fee of 1_000 is 4
+        contract.internal_deposit(&account_a, &account_c, 4);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                Some(account_c.clone()),
+                "".to_string(),
+            )
+            .detach();
+
+        // the receiver returned 400 of 1_000 and deposited the other 600
+        contract.internal_deposit(&account_a, &account_c, 600);
+        contract.on_flash_swap(pool_id.clone(), Ok(U128(600)));
+        assert!(contract.get_flash_swap(pool_id.clone()).is_none());
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_c),
+            Some(0)
+        );
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.1, 1_000_004);
+    }
+
+    #[test]
+    fn test_flash_swap_returned() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        // the fee is paid on the whole amount even if all of it is returned
+        contract.internal_deposit(&account_a, &account_c, 4);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                Some(account_c.clone()),
+                "".to_string(),
+            )
+            .detach();
+        contract.on_flash_swap(pool_id, Ok(U128(0)));
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.1, 1_000_004);
+    }
+
+    #[test]
+    fn test_flash_swap_not_repaid() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        contract.internal_deposit(&account_a, &account_b, 4);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                None,
+                "".to_string(),
+            )
+            .detach();
+
+        // 500 of the 1_002 is deposited, the pool is unlocked and the rest is owed
+        contract.internal_deposit(&account_a, &account_b, 500);
+        contract.on_flash_swap(pool_id.clone(), Ok(U128(1_000)));
+        assert!(!contract.is_pool_locked(&pool_id));
+        let debt = contract.get_flash_debt(account_a.clone()).unwrap();
+        assert_eq!((debt.pool_id, debt.amount), (pool_id, U128(502)));
+
+        contract.internal_deposit(&account_a, &account_b, 502);
+        assert!(contract.repay_flash_debt().is_none());
+        assert!(contract.get_flash_debt(account_a).is_none());
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_001_006);
+    }
+
+    #[test]
+    #[should_panic(expected = "E112: Flash debt has to be repaid before the next flash swap")]
+    fn test_flash_swap_with_debt() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 8);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                None,
+                "".to_string(),
+            )
+            .detach();
+        contract.on_flash_swap(get_pool_key(&account_b, &account_c), Ok(U128(1_000)));
+
+        contract
+            .flash_swap(account_c, account_b, U128(1_000), None, "".to_string())
+            .detach();
+    }
+
+    #[test]
+    #[should_panic(expected = "E203: Insufficient deposit of token_in.near")]
+    fn test_flash_swap_without_fee() {
+        let (mut contract, _, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                None,
+                "".to_string(),
+            )
+            .detach();
+    }
+
+    #[test]
+    fn test_flash_swap_oracle() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool_id = get_pool_key(&account_b, &account_c);
+
+        // the reserves lent from 100 to 200 aren't seen by the oracle
+        set_block_timestamp_sec(100);
+        contract.internal_deposit(&account_a, &account_c, 1_505);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(500_000),
+                Some(account_c.clone()),
+                "".to_string(),
+            )
+            .detach();
+        set_block_timestamp_sec(200);
+        contract.on_flash_swap(pool_id.clone(), Ok(U128(0)));
+
+        // price_a is 1.0 until 100 and 1.001505 with the fee after
+        let twap = contract.get_twap(pool_id, 200);
+        assert_eq!(twap.price_a, U256::from(1_000_752_500_000_000_000u128));
+    }
+
+    #[test]
+    #[should_panic(expected = "E110: Pool is locked by a flash swap")]
+    fn test_flash_swap_locks_pool() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 4);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                None,
+                "".to_string(),
+            )
+            .detach();
+
+        contract.internal_swap(&account_b, &account_c, 1_000, 900);
+    }
+
+    #[test]
+    fn test_agent_swap_reserves_output() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            990,
+        ));
+        let pool = contract
+            .get_pool(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!((pool.reserved_a.0, pool.reserved_b.0), (0, 990));
+
+        // the expected output at the on-chain price is reserved when it's larger
+        contract.set_reserve_expected_output(true);
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+        assert_eq!(
+            contract
+                .get_pool(account_b.clone(), account_c.clone())
+                .unwrap()
+                .reserved_b
+                .0,
+            990 + 999
+        );
+
+        // reservations are released once the agent responds
+        assert!(contract
+            .on_agent_market_maker_response(0, Ok(U128(995)))
+            .is_some());
+        assert_eq!(
+            contract
+                .get_pool(account_b.clone(), account_c.clone())
+                .unwrap()
+                .reserved_b
+                .0,
+            999
+        );
+        assert!(contract
+            .on_agent_market_maker_response(1, Err(PromiseError::Failed))
+            .is_none());
+        assert_eq!(
+            contract
+                .get_pool(account_b.clone(), account_c.clone())
+                .unwrap()
+                .reserved_b
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "E113: Liquidity is reserved for pending swaps")]
+    fn test_remove_liquidity_reserved_for_pending_swap() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000_000);
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000_000,
+            400_000,
+        ));
+
+        // only 300_000 of token C would be left for the pending swap reserving 400_000
+        contract.remove_liquidity(account_b, account_c, U128(700_000), None, None);
+    }
+
+    #[test]
+    fn test_on_chain_only_pricing() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::OnChainOnly,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+
+        // the swap settles in the same call, no agent request is left pending
+        let result = contract.internal_request_swap(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            990,
+        ));
+        assert!(matches!(result, SwapResult::Settled(Some(U128(999)))));
+        assert!(contract.pending_swaps.get(&0).is_none());
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_001_000);
+        assert_eq!(pool_info.1, 999_001);
+    }
+
+    #[test]
+    fn test_on_chain_pricing_locked_pool() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::OnChainOnly,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_004);
+        contract
+            .flash_swap(
+                account_c.clone(),
+                account_b.clone(),
+                U128(1_000),
+                None,
+                "".to_string(),
+            )
+            .detach();
+        let deposit = contract.get_deposits(&account_a).tokens.get(&account_b);
+
+        // a pool locked by a flash swap can't take the swap, amount_in goes back to deposits
+        let result = contract.internal_request_swap(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+        assert!(matches!(result, SwapResult::Settled(None)));
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            deposit
+        );
+    }
+
+    #[test]
+    fn test_agent_timeout_fallback_pricing() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            990,
+        ));
+
+        // the agent timed out, the swap settles at the on-chain price
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::AgentWithFallback,
+        );
+        assert_eq!(
+            contract.on_agent_market_maker_response(0, Err(PromiseError::Failed)),
+            Some(U128(999))
+        );
+        let pool = contract.get_pool(account_b, account_c).unwrap();
+        assert_eq!(pool.token_b_balance.0, 999_001);
+        assert!(pool.pricing_mode == PricingMode::AgentWithFallback);
+    }
+
+    #[test]
+    fn test_agent_timeout_fallback_reserved_liquidity() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_pricing_mode(
+            account_b.clone(),
+            account_c.clone(),
+            PricingMode::AgentWithFallback,
+        );
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            0,
+        ));
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            1_000,
+            999_500,
+        ));
+
+        // the on-chain output 999 would take the liquidity reserved for the second swap
+        assert!(contract
+            .on_agent_market_maker_response(0, Err(PromiseError::Failed))
+            .is_none());
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_b),
+            Some(1_000)
+        );
+        assert!(contract.pending_swaps.get(&0).is_none());
+        let pool = contract.get_pool(account_b, account_c).unwrap();
+        assert_eq!(pool.token_b_balance.0, 1_000_000);
+        assert_eq!(pool.reserved_b.0, 999_500);
+    }
+
+    #[test]
+    fn test_agent_stats() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.internal_deposit(&account_a, &account_b, 3_000);
+        for _ in 0..3 {
+            contract.run_agent_market_maker(swap_request(
+                account_a.clone(),
+                account_b.clone(),
+                account_c.clone(),
+                1_000,
+                990,
+            ));
+        }
+
+        // the agent responds to the first request 4 blocks later
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .block_height(4)
+            .build());
+        // yields can't be resumed in unit tests, record the response like `internal_resume_swap`
+        let requested_at = contract.pending_swaps.get(&0).unwrap().requested_at;
+        contract.internal_record_agent_response(requested_at);
+        contract.on_agent_market_maker_response(0, Ok(U128(900)));
+        contract.on_agent_market_maker_response(1, Err(PromiseError::Failed));
+
+        let stats = contract.get_agent_stats(None);
+        assert_eq!(stats.agent_account_id.as_str(), "agent.near");
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.timed_out, 1);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.resolved, 1);
+        assert_eq!(stats.pending, 1);
+        assert_eq!(stats.average_latency_blocks, 4);
+    }
+
+    #[test]
+    fn test_agent_slashing() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_config(Config {
+            agent_tolerance: Some(100),
+            ..Config::default()
+        });
+        let agent = AccountId::from_str("agent.near").unwrap();
+        contract.internal_deposit(&agent, &account_c, 100);
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(agent.clone())
+            .build());
+        contract.bond_tokens(account_c.clone(), U128(100));
+
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+        let receiver = AccountId::from_str("receiver.near").unwrap();
+        for _ in 0..2 {
+            contract.run_agent_market_maker(SwapRequest {
+                receiver_id: Some(receiver.clone()),
+                ..swap_request(
+                    account_a.clone(),
+                    account_b.clone(),
+                    account_c.clone(),
+                    1_000,
+                    900,
+                )
+            });
+        }
+
+        // 999 at the on-chain price, the agent underpaid by 49 and the receiver gets it from the bond
+        contract.on_agent_market_maker_response(0, Ok(U128(950)));
+        assert_eq!(
+            contract.get_deposits(&receiver).tokens.get(&account_c),
+            Some(49)
+        );
+        assert_eq!(
+            contract.get_deposits(&account_a).tokens.get(&account_c),
+            Some(0)
+        );
+
+        // the agent overpaid by 103, the 51 left in the bond go back to the pool
+        contract.on_agent_market_maker_response(1, Ok(U128(1_100)));
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.1, 1_000_000 - 950 - 1_100 + 51);
+        assert_eq!(contract.get_agent_bond(agent).tokens[0].1 .0, 0);
+    }
+
+    #[test]
+    fn test_agent_unbonding() {
+        let (mut contract, _, _, account_c) = get_contract();
+        let agent = AccountId::from_str("agent.near").unwrap();
+        contract.internal_deposit(&agent, &account_c, 100);
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(agent.clone())
+            .build());
+        contract.bond_tokens(account_c.clone(), U128(100));
+        contract.set_unbonding_period(60);
+        contract.unbond(
+            BondAsset::Token {
+                token_id: account_c.clone(),
+            },
+            U128(40),
+        );
+
+        // unbonding amounts can still be slashed
+        contract.internal_slash_agent(
+            &swap_request(agent.clone(), account_c.clone(), account_c.clone(), 0, 0),
+            staking::QuoteDeviation {
+                amount: 70,
+                underpaid: true,
+            },
+        );
+        let bond = contract.get_agent_bond(agent.clone());
+        assert_eq!(bond.tokens[0].1 .0, 0);
+        assert_eq!(bond.unbonding[0].amount, 30);
+
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(agent.clone())
+            .block_timestamp(60 * 1_000_000_000)
+            .build());
+        contract.withdraw_unbonded();
+        assert!(contract.get_agent_bond(agent.clone()).unbonding.is_empty());
+        assert_eq!(
+            contract.get_deposits(&agent).tokens.get(&account_c),
+            Some(70 + 30)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "E204: Nothing to withdraw")]
+    fn test_agent_unbonding_delay() {
+        let (mut contract, _, _, account_c) = get_contract();
+        let agent = AccountId::from_str("agent.near").unwrap();
+        contract.internal_deposit(&agent, &account_c, 100);
+        near_sdk::testing_env!(near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(agent)
+            .build());
+        contract.bond_tokens(account_c.clone(), U128(100));
+        contract.unbond(
+            BondAsset::Token {
+                token_id: account_c,
+            },
+            U128(100),
+        );
+        contract.withdraw_unbonded();
+    }
+
+    #[test]
+    fn test_config() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.set_config(Config {
+            response_gas: Gas::from_tgas(50),
+            max_pending_swaps_per_user: Some(1),
+            default_creator_fee: 30,
+            ..Config::default()
+        });
+        let config = serde_json::to_value(contract.get_config()).unwrap();
+        assert_eq!(config["response_gas"], json!("50000000000000"));
+        assert_eq!(config["max_pending_swaps_per_user"], json!(1));
+
+        // pools created without a creator_fee take the default one
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let pool = contract
+            .get_pool(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool.creator_fee, 30);
+
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b,
+            account_c,
+            1_000,
+            990,
+        ));
+        assert_eq!(contract.get_user_pending_swaps(account_a.clone()), 1);
+        contract.on_agent_market_maker_response(0, Err(PromiseError::Failed));
+        assert_eq!(contract.get_user_pending_swaps(account_a), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "E701: Invalid config: swap callback gas is more than 300 TGas")]
+    fn test_config_invalid_gas() {
+        let (mut contract, _, _, _) = get_contract();
+        contract.set_config(Config {
+            response_gas: Gas::from_tgas(280),
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "E701: Invalid config: flash swap gas is more than 300 TGas")]
+    fn test_config_invalid_callback_gas() {
+        let (mut contract, _, _, _) = get_contract();
+        contract.set_config(Config {
+            resolve_flash_swap_gas: Gas::from_tgas(270),
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "E408: Account can't have more than 1 pending swaps")]
+    fn test_max_pending_swaps_per_user() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        contract.set_config(Config {
+            max_pending_swaps_per_user: Some(1),
+            ..Config::default()
+        });
+        contract.internal_deposit(&account_a, &account_b, 2_000);
+        for _ in 0..2 {
+            contract.run_agent_market_maker(swap_request(
+                account_a.clone(),
+                account_b.clone(),
+                account_c.clone(),
+                1_000,
+                990,
+            ));
+        }
+    }
+
+    #[test]
+    fn test_run_agent_event_payload() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            Some(50),
+        );
+        contract.set_config(Config {
+            agent_runner_id: Some(AccountId::from_str("runner.near").unwrap()),
+            agent_max_iterations: Some(3),
+            agent_thread_id: Some("amm".to_string()),
+            agent_env_vars: [("network".to_string(), "mainnet".to_string())].into(),
+            ..Config::default()
+        });
+        contract.internal_deposit(&account_a, &account_b, 1_000);
+
+        let mut request = swap_request(account_a, account_b, account_c, 1_000, 990);
+        request.agent_payment = Some(U128(5));
+        contract.run_agent_market_maker(request);
+        // the payment goes to the NEAR AI runner
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert!(receipts
+            .iter()
+            .any(|receipt| receipt.receiver_id.as_str() == "runner.near"));
+
+        let event = near_sdk::test_utils::get_logs()
+            .into_iter()
+            .find_map(|log| log.strip_prefix("EVENT_JSON:").map(str::to_string))
+            .unwrap();
+        let data = &serde_json::from_str::<serde_json::Value>(&event).unwrap()["data"][0];
+        assert_eq!(data["max_iterations"], json!(3));
+        assert_eq!(data["thread_id"], json!("amm"));
+        assert_eq!(data["amount"], json!("5"));
+        let env_vars: serde_json::Value =
+            serde_json::from_str(data["env_vars"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            env_vars,
+            json!({
+                "fee": "50",
+                "network": "mainnet",
+                "pool_id": "token_in.near:token_out.near",
+                "quote_hint": "994",
+            })
+        );
+    }
+
+    #[test]
+    fn test_agent_fee() {
+        let (mut contract, account_a, account_b, account_c) = get_contract();
+        contract.create_pool(
+            account_b.clone(),
+            U128(1_000_000),
+            account_c.clone(),
+            U128(1_000_000),
+            None,
+        );
+        let agent = AccountId::from_str("agent.near").unwrap();
+        contract.internal_deposit(&account_a, &account_b, 20_000);
+        contract.set_config(Config {
+            agent_fee: 50,
+            ..Config::default()
+        });
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            10_000,
+            9_000,
+        ));
+
+        // 0.5% of amount_in goes to the agent deposits, the rest to the pool
+        assert_eq!(
+            contract.on_agent_market_maker_response(0, Ok(U128(9_800))),
+            Some(U128(9_800))
+        );
+        assert_eq!(
+            contract.get_deposits(&agent).tokens.get(&account_b),
+            Some(50)
+        );
+        let pool_info = contract
+            .get_pool_info(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool_info.0, 1_000_000 + 9_950);
+
+        // taken from amount_out, the receiver gets the rest
+        contract.set_config(Config {
+            agent_fee: 50,
+            agent_fee_side: AgentFeeSide::AmountOut,
+            ..Config::default()
+        });
+        contract.run_agent_market_maker(swap_request(
+            account_a.clone(),
+            account_b.clone(),
+            account_c.clone(),
+            10_000,
+            9_000,
+        ));
+        assert_eq!(
+            contract.on_agent_market_maker_response(1, Ok(U128(9_800))),
+            Some(U128(9_751))
+        );
+        assert_eq!(
+            contract.get_deposits(&agent).tokens.get(&account_c),
+            Some(49)
+        );
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.1, 1_000_000 - 2 * 9_800);
+    }
+
+    // Shares the first deployment minted for every new pool
+    const V0_TOTAL_SHARES: Balance = 1_000_000_000_000_000;
+
+    // State of the first deployment, stored without a version. bob.near has 1_000_000 of
+    // token_in.near deposited and a pool of token_in.near and token_out.near
+    fn write_state_v0() -> (AccountId, AccountId, AccountId) {
+        let account_a: AccountId = AccountId::from_str("bob.near").unwrap();
+        let account_b: AccountId = AccountId::from_str("token_in.near").unwrap();
+        let account_c: AccountId = AccountId::from_str("token_out.near").unwrap();
+
+        let pool_key = get_pool_key(&account_b, &account_c);
+        let mut shares = UnorderedMap::new(StorageKey::Shares {
+            pool_key: pool_key.clone(),
+        });
+        shares.insert(&account_a, &V0_TOTAL_SHARES);
+        let mut pools = UnorderedMap::new(StorageKey::PoolsV0);
+        pools.insert(
+            &pool_key,
+            &PoolV0 {
+                token_a: account_b.clone(),
+                token_b: account_c.clone(),
+                token_a_balance: 1_000_000,
+                token_b_balance: 500_000,
+                total_shares: V0_TOTAL_SHARES,
+                shares,
+            },
+        );
+
+        let mut tokens = UnorderedMap::new(StorageKey::TokenDepositsV0 {
+            account_id: account_a.clone(),
+        });
+        tokens.insert(&account_b, &1_000_000);
+        let mut deposits = UnorderedMap::new(StorageKey::DepositsV0);
+        deposits.insert(&account_a, &AccountDeposits { tokens });
+
+        env::state_write(&ContractV0 {
+            agent: "test-agent".to_string(),
+            agent_account_id: AccountId::from_str("agent.near").unwrap(),
+            pools,
+            deposits,
+        });
+        (account_a, account_b, account_c)
+    }
+
+    #[test]
+    fn test_migrate_from_v0() {
+        let (account_a, account_b, account_c) = write_state_v0();
+        let agent_account_id = AccountId::from_str("agent.near").unwrap();
+        assert_eq!(migration::read_state_version(), 0);
+
+        let mut contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), STATE_VERSION);
+        assert_eq!(contract.agent_account_id, agent_account_id);
+        assert_eq!(contract.config.agent_fee, 0);
+
+        // a second pool, pools are migrated in pages
+        let pool_key = get_pool_key(&account_b, &account_c);
+        let other_key = get_pool_key(&account_b, &agent_account_id);
+        let mut other_pool = contract.pools_v0.get(&pool_key).unwrap();
+        other_pool.token_b = agent_account_id.clone();
+        other_pool.shares = UnorderedMap::new(StorageKey::Shares {
+            pool_key: other_key.clone(),
+        });
+        contract.pools_v0.insert(&other_key, &other_pool);
+
+        assert!(contract
+            .get_pool(account_b.clone(), account_c.clone())
+            .is_none());
+        assert_eq!(contract.migrate_pools(1), 1);
+        assert_eq!(contract.get_pools(0, 10).len(), 1);
+        assert_eq!(contract.migrate_pools(1), 0);
+        assert_eq!(contract.get_pools(0, 10).len(), 2);
+
+        let pool = contract
+            .get_pool(account_b.clone(), account_c.clone())
+            .unwrap();
+        assert_eq!(pool.token_a_balance.0, 1_000_000);
+        assert_eq!(pool.token_b_balance.0, 500_000);
+        assert_eq!(pool.total_shares.0, V0_TOTAL_SHARES + MIN_LOCKED_SHARES);
+        assert_eq!(pool.creator_fee, 0);
+        assert!(pool.pricing_mode == PricingMode::AgentOnly);
+        assert_eq!(
+            contract.get_whitelisted_tokens(),
+            vec![
+                account_b.clone(),
+                account_c.clone(),
+                agent_account_id.clone()
+            ]
+        );
+        assert_eq!(
+            contract
+                .pools
+                .get(&pool_key)
+                .unwrap()
+                .shares
+                .get(&account_a),
+            Some(V0_TOTAL_SHARES)
+        );
+
+        // the version 0 deposits are frozen, not in the deposits of the account
+        assert!(contract.get_user_deposits(account_a.clone()).is_empty());
+        assert_eq!(
+            contract.get_deposits_v0(account_a.clone()),
+            vec![(account_b.clone(), U128(1_000_000))]
+        );
+
+        // the migrated pool can be used, migrating the current state changes nothing
+        contract.internal_swap(&account_b, &account_c, 1_000, 499);
+        env::state_write(&contract);
+        let contract = Contract::migrate();
+        let pool_info = contract.get_pool_info(account_b, account_c).unwrap();
+        assert_eq!(pool_info.0, 1_001_000);
+        assert_eq!(pool_info.1, 499_501);
+        assert_eq!(contract.get_pools(0, 10).len(), 2);
+    }
+
+    #[test]
+    fn test_reconcile_deposits_v0() {
+        let (account_a, account_b, _) = write_state_v0();
+        let mut contract = Contract::migrate();
+
+        // the contract account credits the balance checked against the token transfers
+        assert_eq!(contract.get_accounts_v0(0, 10), vec![account_a.clone()]);
+        let left = contract.reconcile_deposits_v0(vec![(
+            account_a.clone(),
+            vec![(account_b.clone(), U128(400))],
+        )]);
+        assert_eq!(left, 0);
+        assert_eq!(
+            contract.get_user_deposits(account_a.clone()),
+            vec![(account_b, U128(400))]
+        );
+        assert!(contract.get_deposits_v0(account_a).is_empty());
+    }
+
+    #[test]
+    fn test_migrated_pool_full_exit() {
+        let (account_a, account_b, account_c) = write_state_v0();
+        let mut contract = Contract::migrate();
+        contract.migrate_pools(1);
+
+        // the last liquidity provider removes all its shares, the locked shares keep reserves
+        let (amount_b, amount_c) =
+            contract.internal_remove_liquidity(&account_b, &account_c, V0_TOTAL_SHARES, &account_a);
+        assert_eq!((amount_b, amount_c), (999_999, 499_999));
+        let pool = contract.get_pool(account_b, account_c).unwrap();
+        assert_eq!(pool.total_shares.0, MIN_LOCKED_SHARES);
+        assert_eq!((pool.token_a_balance.0, pool.token_b_balance.0), (1, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "E102: Pool already exists")]
+    fn test_create_pool_not_migrated() {
+        let (account_a, account_b, account_c) = write_state_v0();
+        let mut contract = Contract::migrate();
+
+        contract.internal_deposit(&account_a, &account_b, 1_000_000);
+        contract.internal_deposit(&account_a, &account_c, 1_000_000);
+        contract.create_pool(account_b, U128(1_000_000), account_c, U128(1_000_000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "E802: Unknown state version 9")]
+    fn test_migrate_unknown_version() {
+        let (contract, _, _, _) = get_contract();
+        env::state_write(&contract);
+        env::storage_write(
+            &StorageKey::StateVersion.into_storage_key(),
+            &9u32.to_le_bytes(),
+        );
+        Contract::migrate();
+    }
+
+    fn set_upgrade_context(predecessor_id: &str, timestamp_sec: u64, code: &[u8]) {
+        let mut context = near_sdk::test_utils::VMContextBuilder::new()
+            .predecessor_account_id(AccountId::from_str(predecessor_id).unwrap())
+            .block_timestamp(timestamp_sec * 1_000_000_000)
+            .build();
+        context.input = code.into();
+        near_sdk::testing_env!(context);
+    }
+
+    // The code deployed by the receipt of the last upgrade, which also calls migrate
+    fn deployed_code() -> Vec<u8> {
+        use near_sdk::mock::MockAction;
+        use near_sdk::test_utils::get_created_receipts;
+
+        let receipts = get_created_receipts();
+        let actions = &receipts.last().unwrap().actions;
+        assert_eq!(actions.len(), 2);
+        match &actions[1] {
+            MockAction::FunctionCallWeight { method_name, .. } => {
+                assert_eq!(method_name, b"migrate")
+            }
+            action => panic!("Unexpected action {:?}", action),
+        }
+        match &actions[0] {
+            MockAction::DeployContract { code, .. } => code.clone(),
+            action => panic!("Unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_upgrade() {
+        set_upgrade_context("alice.near", 0, b"code v2");
+        let _ = Contract::upgrade();
+        assert_eq!(deployed_code(), b"code v2");
+
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: Some(AccountId::from_str("dao.near").unwrap()),
+            time_lock_sec: 0,
+        });
+        set_upgrade_context("dao.near", 0, b"code v3");
+        let _ = Contract::upgrade();
+        assert_eq!(deployed_code(), b"code v3");
+    }
+
+    #[test]
+    #[should_panic(expected = "E901: Only the contract account or the DAO can upgrade")]
+    fn test_upgrade_not_authority() {
+        set_upgrade_context("bob.near", 0, b"code v2");
+        let _ = Contract::upgrade();
+    }
+
+    #[test]
+    #[should_panic(expected = "E902: Upgrades are time locked, the code has to be staged")]
+    fn test_upgrade_time_locked() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 100,
+        });
+        set_upgrade_context("alice.near", 0, b"code v2");
+        let _ = Contract::upgrade();
+    }
+
+    #[test]
+    fn test_staged_code() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: Some(AccountId::from_str("dao.near").unwrap()),
+            time_lock_sec: 100,
+        });
+        set_upgrade_context("dao.near", 10, b"code v2");
+        Contract::stage_code();
+        let staged_code = Contract::get_staged_code().unwrap();
+        assert_eq!(
+            staged_code.hash,
+            "a17c35b19bef66ae21bfe6a88c37a7a929ecc83c970d6ff223bec1e6e39484ca"
+        );
+        assert_eq!(staged_code.available_at, 110);
+
+        Contract::cancel_staged_code();
+        assert!(Contract::get_staged_code().is_none());
+
+        // staging again restarts the time lock
+        set_upgrade_context("alice.near", 50, b"code v3");
+        Contract::stage_code();
+        assert_eq!(Contract::get_staged_code().unwrap().available_at, 150);
+
+        set_upgrade_context("dao.near", 150, b"");
+        let _ = Contract::deploy_staged_code();
+        assert_eq!(deployed_code(), b"code v3");
+        assert!(Contract::get_staged_code().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "E905: Staged code can be deployed at 100")]
+    fn test_staged_code_time_locked() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 100,
+        });
+        set_upgrade_context("alice.near", 0, b"code v2");
+        Contract::stage_code();
+        set_upgrade_context("alice.near", 99, b"");
+        let _ = Contract::deploy_staged_code();
+    }
+
+    #[test]
+    #[should_panic(expected = "E905: Staged code can be deployed at 210")]
+    fn test_raised_time_lock_delays_staged_code() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: Some(AccountId::from_str("dao.near").unwrap()),
+            time_lock_sec: 100,
+        });
+        set_upgrade_context("dao.near", 10, b"code v2");
+        Contract::stage_code();
+
+        // the DAO raises the time lock, the staged code waits for the new one
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: Some(AccountId::from_str("dao.near").unwrap()),
+            time_lock_sec: 200,
+        });
+        assert_eq!(Contract::get_upgrade_config().time_lock_sec, 200);
+        assert_eq!(Contract::get_staged_code().unwrap().available_at, 210);
+        set_upgrade_context("dao.near", 110, b"");
+        let _ = Contract::deploy_staged_code();
+    }
+
+    #[test]
+    #[should_panic(expected = "E901: Only the contract account or the DAO can upgrade")]
+    fn test_set_upgrade_config_not_authority() {
+        set_upgrade_context("bob.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 0,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "E902: Upgrades are time locked, the code has to be staged")]
+    fn test_lower_time_lock_staged() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 100,
+        });
+        set_upgrade_context("alice.near", 0, b"code v2");
+        Contract::stage_code();
+
+        // lowering the time lock waits for the current one, it can't skip the staged code
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 0,
+        });
+        assert_eq!(Contract::get_upgrade_config().time_lock_sec, 100);
+        assert_eq!(
+            Contract::get_staged_upgrade_config().unwrap().available_at,
+            100
+        );
+        set_upgrade_context("alice.near", 1, b"code v3");
+        let _ = Contract::upgrade();
+    }
+
+    #[test]
+    fn test_staged_upgrade_config() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: Some(AccountId::from_str("dao.near").unwrap()),
+            time_lock_sec: 100,
+        });
+        set_upgrade_context("alice.near", 10, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 0,
+        });
+
+        set_upgrade_context("dao.near", 110, b"");
+        Contract::apply_staged_upgrade_config();
+        assert_eq!(Contract::get_upgrade_config().time_lock_sec, 0);
+        assert!(Contract::get_staged_upgrade_config().is_none());
+
+        set_upgrade_context("alice.near", 110, b"code v2");
+        let _ = Contract::upgrade();
+        assert_eq!(deployed_code(), b"code v2");
+    }
+
+    #[test]
+    #[should_panic(expected = "E907: Staged upgrade config can be applied at 100")]
+    fn test_staged_upgrade_config_time_locked() {
+        set_upgrade_context("alice.near", 0, b"");
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 100,
+        });
+        Contract::set_upgrade_config(UpgradeConfig {
+            dao_id: None,
+            time_lock_sec: 10,
+        });
+        set_upgrade_context("alice.near", 99, b"");
+        Contract::apply_staged_upgrade_config();
+    }
+
+    // State machine test of the pools: random sequences of deposits, liquidity changes, agent
+    // swaps and withdrawals, with the invariants checked after every step. Operations that
+    // panic are rolled back, like failed transactions
+    mod invariants {
+        use super::*;
+        use math::U256;
+        use near_sdk::borsh;
+        use near_sdk::test_utils::VMContextBuilder;
+        use near_sdk::{test_vm_config, MockedBlockchain, RuntimeFeesConfig, VMContext};
+        use proptest::prelude::*;
+        use std::any::Any;
+        use std::cell::OnceCell;
+        use std::collections::HashMap;
+
+        const AGENT_BOND: Balance = 1_000_000_000_000;
+
+        #[derive(Debug, Clone)]
+        enum Op {
+            Deposit {
+                user: usize,
+                token: usize,
+                amount: Balance,
+            },
+            // amounts are percents of the user deposits
+            AddLiquidity {
+                user: usize,
+                percent_a: u8,
+                percent_b: u8,
+            },
+            RemoveLiquidity {
+                user: usize,
+                percent: u8,
+            },
+            RequestSwap {
+                user: usize,
+                token_in: usize,
+                percent: u8,
+                min_out_percent: u8,
+            },
+            // amount_out is a percent of the on-chain output, the swap times out if None
+            AgentResponse {
+                swap: usize,
+                percent: Option<u8>,
+            },
+            Withdraw {
+                user: usize,
+                token: usize,
+                percent: u8,
+            },
+        }
+
+        fn op_strategy() -> impl Strategy<Value = Op> {
+            prop_oneof![
+                (0..2usize, 0..2usize, 1..1_000_000u128).prop_map(|(user, token, amount)| {
+                    Op::Deposit {
+                        user,
+                        token,
+                        amount,
+                    }
+                }),
+                (0..2usize, 0..=100u8, 0..=100u8).prop_map(|(user, percent_a, percent_b)| {
+                    Op::AddLiquidity {
+                        user,
+                        percent_a,
+                        percent_b,
+                    }
+                }),
+                (0..2usize, 0..=100u8)
+                    .prop_map(|(user, percent)| Op::RemoveLiquidity { user, percent }),
+                (0..2usize, 0..2usize, 0..=50u8, 0..=100u8).prop_map(
+                    |(user, token_in, percent, min_out_percent)| Op::RequestSwap {
+                        user,
+                        token_in,
+                        percent,
+                        min_out_percent,
+                    }
+                ),
+                (0..8usize, proptest::option::weighted(0.8, 0..=120u8))
+                    .prop_map(|(swap, percent)| Op::AgentResponse { swap, percent }),
+                (0..2usize, 0..2usize, 0..=100u8).prop_map(|(user, token, percent)| Op::Withdraw {
+                    user,
+                    token,
+                    percent
+                }),
+            ]
+        }
+
+        struct Model {
+            users: Vec<AccountId>,
+            tokens: Vec<AccountId>,
+            agent: AccountId,
+            // Tokens transferred to the contract minus the tokens sent out, per token
+            supply: Vec<Balance>,
+            // request_id of the swaps waiting for the agent
+            pending: Vec<u64>,
+            // k and total_shares of the pool after the last step
+            last_k: U256,
+            last_shares: Balance,
+            // Storage deposit locked for the pool and its oracle, paid by its creator bob
+            pool_storage: Balance,
+            // Storage deposit locked by the token deposits of each user
+            deposit_storage: Vec<Balance>,
+        }
+
+        fn context(predecessor: &AccountId, deposit: Balance) -> VMContext {
+            VMContextBuilder::new()
+                .predecessor_account_id(predecessor.clone())
+                .attached_deposit(NearToken::from_yoctonear(deposit))
+                .build()
+        }
+
+        // Building the VM config takes most of the time of a call, it's built once per thread
+        fn vm_config<T: Clone + 'static>(build: fn() -> T) -> T {
+            thread_local! {
+                static VM_CONFIG: OnceCell<Box<dyn Any>> = const { OnceCell::new() };
+            }
+            VM_CONFIG.with(|config| {
+                config
+                    .get_or_init(|| Box::new(build()))
+                    .downcast_ref::<T>()
+                    .unwrap()
+                    .clone()
+            })
+        }
+
+        fn set_blockchain(context: VMContext, storage: HashMap<Vec<u8>, Vec<u8>>) {
+            env::set_blockchain_interface(MockedBlockchain::new(
+                context,
+                vm_config(test_vm_config),
+                RuntimeFeesConfig::test(),
+                vec![],
+                storage,
+                Default::default(),
+                None,
+            ));
+        }
+
+        // Run the call like a transaction, the contract and its storage are restored if it panics
+        fn transact<R>(
+            contract: &mut Contract,
+            predecessor: &AccountId,
+            deposit: Balance,
+            call: impl FnOnce(&mut Contract) -> R,
+        ) -> Option<R> {
+            let state = borsh::to_vec(contract).unwrap();
+            let storage = near_sdk::mock::with_mocked_blockchain(|b| b.take_storage());
+            set_blockchain(context(predecessor, deposit), storage.clone());
+
+            let result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| call(&mut *contract)));
+            if result.is_err() {
+                set_blockchain(context(predecessor, deposit), storage);
+                *contract = borsh::from_slice(&state).unwrap();
+            }
+            result.ok()
+        }
+
+        fn deposit_of(contract: &Contract, account_id: &AccountId, token: &AccountId) -> Balance {
+            contract
+                .get_deposits(account_id)
+                .tokens
+                .get(token)
+                .unwrap_or(0)
+        }
+
+        fn pool(contract: &Contract, model: &Model) -> Pool {
+            contract
+                .pools
+                .get(&get_pool_key(&model.tokens[0], &model.tokens[1]))
+                .unwrap()
+        }
+
+        fn setup() -> (Contract, Model) {
+            // the storage of the previous case is dropped
+            set_blockchain(VMContextBuilder::new().build(), HashMap::new());
+            let (mut contract, bob, token_a, token_b) = get_contract();
+            let alice = AccountId::from_str("alice.near").unwrap();
+            let agent = AccountId::from_str("agent.near").unwrap();
+            contract.add_whitelisted_tokens(vec![token_a.clone(), token_b.clone()]);
+            contract.create_pool(
+                token_a.clone(),
+                U128(500_000),
+                token_b.clone(),
+                U128(500_000),
+                Some(30),
+            );
+            // any deviation from the on-chain price is slashed from the agent bond
+            contract.set_config(Config {
+                agent_fee: 10,
+                agent_tolerance: Some(0),
+                ..Config::default()
+            });
+            let storage = near_sdk::mock::with_mocked_blockchain(|b| b.take_storage());
+            set_blockchain(context(&agent, 0), storage);
+            for token in [&token_a, &token_b] {
+                contract.internal_deposit(&agent, token, AGENT_BOND);
+                contract.bond_tokens(token.clone(), U128(AGENT_BOND));
+            }
+            // both users can pay for the storage of their pending swaps
+            contract.storage_balances.insert(
+                &alice,
+                &StorageBalance {
+                    total: STORAGE_BALANCE,
+                    locked: 0,
+                },
+            );
+
+            let pool = contract
+                .pools
+                .get(&get_pool_key(&token_a, &token_b))
+                .unwrap();
+            let pool_storage =
+                STORAGE_BALANCE - contract.storage_balance_of(bob.clone()).available.0;
+            let model = Model {
+                users: vec![bob, alice],
+                tokens: vec![token_a, token_b],
+                agent,
+                supply: vec![1_000_000 + AGENT_BOND; 2],
+                pending: vec![],
+                last_k: U256::from(pool.token_a_balance) * U256::from(pool.token_b_balance),
+                last_shares: pool.total_shares,
+                pool_storage,
+                deposit_storage: vec![0; 2],
+            };
+            (contract, model)
+        }
+
+        fn apply(contract: &mut Contract, model: &mut Model, op: Op) {
+            match op {
+                Op::Deposit {
+                    user,
+                    token,
+                    amount,
+                } => {
+                    let msg = json!({ "Execute": { "actions": [{ "Deposit": {} }] } });
+                    let account_id = model.users[user].clone();
+                    let available = contract.storage_balance_of(account_id.clone()).available.0;
+                    if transact(contract, &model.tokens[token], 0, |contract| {
+                        contract.ft_on_transfer(account_id.clone(), U128(amount), msg.to_string())
+                    })
+                    .is_some()
+                    {
+                        model.supply[token] += amount;
+                        model.deposit_storage[user] +=
+                            available - contract.storage_balance_of(account_id).available.0;
+                    }
+                }
+                Op::AddLiquidity {
+                    user,
+                    percent_a,
+                    percent_b,
+                } => {
+                    let user = &model.users[user];
+                    let amount_a =
+                        deposit_of(contract, user, &model.tokens[0]) * percent_a as Balance / 100;
+                    let amount_b =
+                        deposit_of(contract, user, &model.tokens[1]) * percent_b as Balance / 100;
+                    let (token_a, token_b) = (model.tokens[0].clone(), model.tokens[1].clone());
+                    transact(contract, user, 0, |contract| {
+                        contract.add_liquidity_from_deposits(
+                            token_a,
+                            token_b,
+                            U128(amount_a),
+                            U128(amount_b),
+                            None,
+                        )
+                    });
+                }
+                Op::RemoveLiquidity { user, percent } => {
+                    let user = &model.users[user];
+                    let shares = pool(contract, model).shares.get(user).unwrap_or(0)
+                        * percent as Balance
+                        / 100;
+                    let (token_a, token_b) = (model.tokens[0].clone(), model.tokens[1].clone());
+                    transact(contract, user, 0, |contract| {
+                        contract.remove_liquidity(token_a, token_b, U128(shares), None, None)
+                    });
+                }
+                Op::RequestSwap {
+                    user,
+                    token_in,
+                    percent,
+                    min_out_percent,
+                } => {
+                    let user = &model.users[user];
+                    let (token_in, token_out) = (
+                        model.tokens[token_in].clone(),
+                        model.tokens[1 - token_in].clone(),
+                    );
+                    let amount_in =
+                        deposit_of(contract, user, &token_in) * percent as Balance / 100;
+                    let min_amount_out = if amount_in > 0 {
+                        pool(contract, model).get_amount_out(&token_in, amount_in)
+                            * min_out_percent as Balance
+                            / 100
+                    } else {
+                        0
+                    };
+                    let request_id = contract.next_request_id;
+                    if transact(contract, user, 0, |contract| {
+                        contract.swap(
+                            token_in,
+                            token_out,
+                            U128(amount_in),
+                            U128(min_amount_out),
+                            None,
+                            None,
+                        )
+                    })
+                    .is_some()
+                    {
+                        model.pending.push(request_id);
+                    }
+                }
+                Op::AgentResponse { swap, percent } => {
+                    if model.pending.is_empty() {
+                        return;
+                    }
+                    let index = swap % model.pending.len();
+                    let request_id = model.pending[index];
+                    let request = contract.pending_swaps.get(&request_id).unwrap().request;
+                    let token_out = model
+                        .tokens
+                        .iter()
+                        .position(|token| token == &request.token_out)
+                        .unwrap();
+                    let amount_out = percent.map(|percent| {
+                        pool(contract, model).get_amount_out(&request.token_in, request.amount_in.0)
+                            * percent as Balance
+                            / 100
+                    });
+                    let current_account_id = env::current_account_id();
+                    if let Some(result) = transact(contract, &current_account_id, 0, |contract| {
+                        contract.on_agent_market_maker_response(
+                            request_id,
+                            amount_out.map(U128).ok_or(PromiseError::Failed),
+                        )
+                    }) {
+                        model.pending.remove(index);
+                        // the output is sent to the receiver
+                        if let Some(amount_out) = result {
+                            model.supply[token_out] -= amount_out.0;
+                        }
+                    }
+                }
+                Op::Withdraw {
+                    user,
+                    token,
+                    percent,
+                } => {
+                    let user = &model.users[user];
+                    let amount =
+                        deposit_of(contract, user, &model.tokens[token]) * percent as Balance / 100;
+                    let token_id = model.tokens[token].clone();
+                    if transact(contract, user, 1, |contract| {
+                        contract.withdraw(token_id, Some(U128(amount)))
+                    })
+                    .is_some()
+                    {
+                        model.supply[token] -= amount;
+                    }
+                }
+            }
+        }
+
+        fn check_invariants(contract: &Contract, model: &mut Model) {
+            let pool = pool(contract, model);
+            let pending: Vec<PendingSwap> = model
+                .pending
+                .iter()
+                .map(|request_id| contract.pending_swaps.get(request_id).unwrap())
+                .collect();
+
+            // every token transferred in is in deposits, the pool, pending swaps or the agent bond
+            let bond = contract.get_agent_bond(model.agent.clone());
+            for (index, token) in model.tokens.iter().enumerate() {
+                let deposits: Balance = model
+                    .users
+                    .iter()
+                    .chain([&model.agent])
+                    .map(|account_id| deposit_of(contract, account_id, token))
+                    .sum();
+                let pending_in: Balance = pending
+                    .iter()
+                    .filter(|swap| &swap.request.token_in == token)
+                    .map(|swap| swap.request.amount_in.0)
+                    .sum();
+                let bonded = bond
+                    .tokens
+                    .iter()
+                    .filter(|(token_id, _)| token_id == token)
+                    .map(|(_, amount)| amount.0)
+                    .sum::<Balance>();
+                let (balance, _) = pool.balances(token);
+                assert_eq!(
+                    deposits + pending_in + bonded + balance,
+                    model.supply[index],
+                    "{} isn't conserved",
+                    token
+                );
+
+                // reserves stay positive and cover the output reserved for pending swaps
+                let reserved: Balance = pending
+                    .iter()
+                    .filter(|swap| &swap.request.token_out == token)
+                    .map(|swap| swap.reserved_out)
+                    .sum();
+                assert_eq!(pool.reserved(token), reserved);
+                assert!(balance > reserved);
+            }
+
+            // the shares of the users and the locked shares add up to the total
+            let user_shares: Balance = model
+                .users
+                .iter()
+                .map(|account_id| pool.shares.get(account_id).unwrap_or(0))
+                .sum();
+            assert_eq!(user_shares + MIN_LOCKED_SHARES, pool.total_shares);
+
+            // k per share never decreases: k / shares^2 >= last_k / last_shares^2
+            let k = U256::from(pool.token_a_balance) * U256::from(pool.token_b_balance);
+            let shares = U256::from(pool.total_shares);
+            let last_shares = U256::from(model.last_shares);
+            assert!(k * last_shares * last_shares >= model.last_k * shares * shares);
+            model.last_k = k;
+            model.last_shares = pool.total_shares;
+
+            for (user, account_id) in model.users.iter().enumerate() {
+                let count = pending
+                    .iter()
+                    .filter(|swap| &swap.request.sender_id == account_id)
+                    .count() as u32;
+                assert_eq!(contract.get_user_pending_swaps(account_id.clone()), count);
+
+                // the storage deposit locked by the pending swaps of the user and by the pool
+                let mut storage_cost: Balance = pending
+                    .iter()
+                    .filter(|swap| &swap.request.sender_id == account_id)
+                    .map(|swap| swap.storage_cost)
+                    .sum();
+                if user == 0 {
+                    storage_cost += model.pool_storage;
+                }
+                storage_cost += model.deposit_storage[user];
+                let balance = contract.storage_balance_of(account_id.clone());
+                assert_eq!(balance.total.0 - balance.available.0, storage_cost);
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn prop_pool_invariants(ops in proptest::collection::vec(op_strategy(), 1..50)) {
+                let (mut contract, mut model) = setup();
+                check_invariants(&contract, &mut model);
+                for op in ops {
+                    apply(&mut contract, &mut model, op);
+                    check_invariants(&contract, &mut model);
+                }
+            }
+        }
+    }
 }
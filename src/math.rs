@@ -0,0 +1,281 @@
+// Checked AMM math. Intermediate products are computed in U256, so they never overflow,
+// and every result is rounded in favor of the pool:
+// - amounts paid out by the pool (swap output, removed liquidity, minted shares) round down
+// - amounts paid into the pool (paired liquidity amount) round up
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use std::io;
+
+type Balance = u128;
+
+mod u256 {
+    #![allow(clippy::manual_div_ceil, clippy::assign_op_pattern)]
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+    uint::construct_uint! {
+        pub struct U512(8);
+    }
+}
+pub use u256::{U256, U512};
+
+// Stored as the four little-endian u64 words
+impl BorshSerialize for U256 {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        BorshSerialize::serialize(&self.0, writer)
+    }
+}
+
+impl BorshDeserialize for U256 {
+    fn deserialize_reader<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(U256(<[u64; 4]>::deserialize_reader(reader)?))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MathError {
+    ZeroAmount,
+    InsufficientLiquidity,
+    Overflow,
+}
+
+fn to_balance(value: U256) -> Result<Balance, MathError> {
+    if value > U256::from(u128::MAX) {
+        Err(MathError::Overflow)
+    } else {
+        Ok(value.as_u128())
+    }
+}
+
+// a * b / c, rounded down
+pub fn mul_div_floor(a: Balance, b: Balance, c: Balance) -> Result<Balance, MathError> {
+    if c == 0 {
+        return Err(MathError::InsufficientLiquidity);
+    }
+    to_balance(U256::from(a) * U256::from(b) / U256::from(c))
+}
+
+// a * b / c, rounded up
+pub fn mul_div_ceil(a: Balance, b: Balance, c: Balance) -> Result<Balance, MathError> {
+    if c == 0 {
+        return Err(MathError::InsufficientLiquidity);
+    }
+    let c = U256::from(c);
+    to_balance((U256::from(a) * U256::from(b) + c - 1) / c)
+}
+
+pub fn add(a: Balance, b: Balance) -> Result<Balance, MathError> {
+    a.checked_add(b).ok_or(MathError::Overflow)
+}
+
+// Subtract an amount taken out of a reserve, the reserve can't become empty
+pub fn sub_reserve(reserve: Balance, amount: Balance) -> Result<Balance, MathError> {
+    if amount >= reserve {
+        Err(MathError::InsufficientLiquidity)
+    } else {
+        Ok(reserve - amount)
+    }
+}
+
+// Constant product output for amount_in, rounded down
+pub fn get_amount_out(
+    amount_in: Balance,
+    reserve_in: Balance,
+    reserve_out: Balance,
+) -> Result<Balance, MathError> {
+    if amount_in == 0 {
+        return Err(MathError::ZeroAmount);
+    }
+    if reserve_in == 0 || reserve_out == 0 {
+        return Err(MathError::InsufficientLiquidity);
+    }
+    mul_div_floor(amount_in, reserve_out, add(reserve_in, amount_in)?)
+}
+
+// Constant product input that buys amount_out, rounded up
+pub fn get_amount_in(
+    amount_out: Balance,
+    reserve_in: Balance,
+    reserve_out: Balance,
+) -> Result<Balance, MathError> {
+    if amount_out == 0 {
+        return Err(MathError::ZeroAmount);
+    }
+    mul_div_ceil(
+        reserve_in,
+        amount_out,
+        sub_reserve(reserve_out, amount_out)?,
+    )
+}
+
+// Spot price of one token in the other token, scaled by precision. It can exceed Balance::MAX
+// for imbalanced reserves, so it's kept in U256. Zero without reserves
+pub fn price(reserve: Balance, reserve_other: Balance, precision: Balance) -> U256 {
+    (U256::from(reserve_other) * U256::from(precision))
+        .checked_div(U256::from(reserve))
+        .unwrap_or_default()
+}
+
+// Amount of the other token that matches amount at the reserve ratio, rounded up
+pub fn quote_paired_amount(
+    amount: Balance,
+    reserve: Balance,
+    reserve_other: Balance,
+) -> Result<Balance, MathError> {
+    mul_div_ceil(amount, reserve_other, reserve)
+}
+
+// Shares minted for the initial reserves of a pool, sqrt(reserve_a * reserve_b) rounded down.
+// Fees only grow the reserves, so every share keeps at least one unit of their geometric mean
+pub fn initial_shares(reserve_a: Balance, reserve_b: Balance) -> Balance {
+    (U256::from(reserve_a) * U256::from(reserve_b))
+        .integer_sqrt()
+        .as_u128()
+}
+
+// Shares minted for amount added to reserve, rounded down
+pub fn shares_for_amount(
+    amount: Balance,
+    reserve: Balance,
+    total_shares: Balance,
+) -> Result<Balance, MathError> {
+    mul_div_floor(amount, total_shares, reserve)
+}
+
+// Part of reserve returned for burned shares, rounded down
+pub fn amount_for_shares(
+    shares: Balance,
+    reserve: Balance,
+    total_shares: Balance,
+) -> Result<Balance, MathError> {
+    if shares == 0 {
+        return Err(MathError::ZeroAmount);
+    }
+    mul_div_floor(reserve, shares, total_shares)
+}
+
+// Part of a single-sided amount to swap before adding liquidity, so that the rest and
+// the swap output match the reserve ratio. fee / fee_divisor of the swapped part doesn't reach
+// the pool, with g = 1 - fee / fee_divisor the swapped part s solves g^2 s^2 + r (1 + g) s = r a:
+// s = (sqrt(r^2 (1 + g)^2 + 4 g^2 r a) - r (1 + g)) / (2 g^2), rounded down.
+// Without a fee it's sqrt(r * (r + a)) - r
+pub fn zap_swap_amount(
+    amount: Balance,
+    reserve_in: Balance,
+    fee: Balance,
+    fee_divisor: Balance,
+) -> Result<Balance, MathError> {
+    if amount == 0 {
+        return Err(MathError::ZeroAmount);
+    }
+    // scaled by fee_divisor: d = fee_divisor, g = (d - fee) / d, fee is below fee_divisor
+    let d = U512::from(fee_divisor);
+    let g = U512::from(fee_divisor - fee);
+    let r = U512::from(reserve_in);
+    let b = r * (d + g);
+    let root = (b * b + U512::from(4) * g * g * r * U512::from(amount)).integer_sqrt();
+    let swap_amount = d * (root - b) / (U512::from(2) * g * g);
+    if swap_amount > U512::from(u128::MAX) {
+        Err(MathError::Overflow)
+    } else {
+        Ok(swap_amount.low_u128())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // 10^9 tokens with 24 decimals
+    const MAX_BALANCE: Balance = 1_000_000_000_000_000_000_000_000_000_000_000;
+
+    #[test]
+    fn test_get_amount_out() {
+        assert_eq!(get_amount_out(1_000, 1_000_000, 1_000_000), Ok(999));
+        assert_eq!(
+            get_amount_out(0, 1_000_000, 1_000_000),
+            Err(MathError::ZeroAmount)
+        );
+        assert_eq!(
+            get_amount_out(1_000, 0, 1_000_000),
+            Err(MathError::InsufficientLiquidity)
+        );
+        assert_eq!(
+            get_amount_out(u128::MAX, u128::MAX, 1),
+            Err(MathError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_initial_shares() {
+        assert_eq!(initial_shares(1_000_000, 1_000_000), 1_000_000);
+        assert_eq!(initial_shares(1_000_000, 500_000), 707_106);
+        assert_eq!(initial_shares(u128::MAX, u128::MAX), u128::MAX);
+    }
+
+    #[test]
+    fn test_mul_div_rounding() {
+        assert_eq!(mul_div_floor(10, 10, 3), Ok(33));
+        assert_eq!(mul_div_ceil(10, 10, 3), Ok(34));
+        assert_eq!(mul_div_ceil(10, 9, 3), Ok(30));
+        assert_eq!(
+            mul_div_floor(u128::MAX, u128::MAX, u128::MAX),
+            Ok(u128::MAX)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_swap_keeps_k(
+            reserve_in in 1..MAX_BALANCE,
+            reserve_out in 1..MAX_BALANCE,
+            amount_in in 1..MAX_BALANCE,
+        ) {
+            let amount_out = get_amount_out(amount_in, reserve_in, reserve_out).unwrap();
+            prop_assert!(amount_out < reserve_out);
+
+            let k_before = U256::from(reserve_in) * U256::from(reserve_out);
+            let k_after =
+                U256::from(reserve_in + amount_in) * U256::from(reserve_out - amount_out);
+            prop_assert!(k_after >= k_before);
+        }
+
+        #[test]
+        fn prop_liquidity_round_trip(
+            reserve in 1..MAX_BALANCE,
+            total_shares in 1..MAX_BALANCE,
+            amount in 1..MAX_BALANCE,
+        ) {
+            let shares = shares_for_amount(amount, reserve, total_shares);
+            prop_assume!(shares.is_ok());
+            let shares = shares.unwrap();
+            prop_assume!(shares > 0 && shares < MAX_BALANCE);
+            let returned =
+                amount_for_shares(shares, reserve + amount, total_shares + shares).unwrap();
+            prop_assert!(returned <= amount);
+        }
+
+        #[test]
+        fn prop_zap_swap_amount(reserve_in in 1..MAX_BALANCE, amount in 1..MAX_BALANCE) {
+            let swap_amount = zap_swap_amount(amount, reserve_in, 0, 10_000).unwrap();
+            prop_assert!(swap_amount <= amount);
+
+            let product = U256::from(reserve_in) * U256::from(reserve_in + amount);
+            let root = U256::from(reserve_in + swap_amount);
+            prop_assert!(root * root <= product);
+            prop_assert!((root + 1) * (root + 1) > product);
+        }
+
+        #[test]
+        fn prop_zap_swap_amount_with_fee(
+            reserve_in in 1..MAX_BALANCE,
+            amount in 1..MAX_BALANCE,
+            fee in 1..200u128,
+        ) {
+            // the fee is made up for by swapping more
+            let swap_amount = zap_swap_amount(amount, reserve_in, fee, 10_000).unwrap();
+            prop_assert!(swap_amount <= amount);
+            prop_assert!(swap_amount >= zap_swap_amount(amount, reserve_in, 0, 10_000).unwrap());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::*;
+use near_sdk::IntoStorageKey;
+
+// Version of the `Contract` layout, bumped with every change of the stored state.
+// Version 0 is the first deployment, it didn't store a version
+pub const STATE_VERSION: u32 = 1;
+
+// Pool of the version 0 state
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct PoolV0 {
+    pub token_a: AccountId,
+    pub token_b: AccountId,
+    pub token_a_balance: Balance,
+    pub token_b_balance: Balance,
+    pub total_shares: Balance,
+    pub shares: UnorderedMap<AccountId, Balance>,
+}
+
+// Contract state of the version 0, its pools and deposits are stored under StorageKey::PoolsV0
+// and StorageKey::DepositsV0
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct ContractV0 {
+    pub agent: String,
+    pub agent_account_id: AccountId,
+    pub pools: UnorderedMap<String, PoolV0>,
+    pub deposits: UnorderedMap<AccountId, AccountDeposits>,
+}
+
+// Contract state in any of the deployed layouts, only built by `migrate`
+#[allow(clippy::large_enum_variant)]
+pub enum VersionedContract {
+    V0(ContractV0),
+    V1(Contract),
+}
+
+impl VersionedContract {
+    // Read the stored state with the layout of its version
+    fn read() -> Self {
+        match read_state_version() {
+            0 => VersionedContract::V0(read_state()),
+            STATE_VERSION => VersionedContract::V1(read_state()),
+            version => ContractError::UnknownStateVersion { version }.panic(),
+        }
+    }
+
+    // Upgrade the state one version at a time up to the current one
+    fn migrate(self) -> Contract {
+        match self {
+            VersionedContract::V0(state) => VersionedContract::V1(state.into()).migrate(),
+            VersionedContract::V1(contract) => contract,
+        }
+    }
+}
+
+// Pools and deposits stay under their version 0 keys. Pools are moved to StorageKey::PoolsV1 by
+// `migrate_pools` in pages, so the migration doesn't run out of gas. The first deployment didn't
+// take deposits for created pools and swaps, so its deposits can't be trusted: they are frozen
+// until the contract account reconciles them with `reconcile_deposits_v0`, a page of accounts
+// from `get_accounts_v0` at a time
+impl From<ContractV0> for Contract {
+    fn from(state: ContractV0) -> Self {
+        let ContractV0 {
+            agent,
+            agent_account_id,
+            pools,
+            deposits,
+        } = state;
+
+        let mut contract = Contract::new(agent, agent_account_id);
+        contract.pools_v0 = pools;
+        contract.deposits_v0 = deposits;
+        contract
+    }
+}
+
+impl Contract {
+    // The first deployment didn't lock shares, MIN_LOCKED_SHARES are added to the total without
+    // an owner so the last liquidity provider can remove all its shares and the reserves stay
+    // positive
+    fn internal_migrate_pool(&mut self, pool_key: &String, pool: PoolV0) {
+        let mut pool = Pool {
+            token_a: pool.token_a,
+            token_b: pool.token_b,
+            token_a_balance: pool.token_a_balance,
+            token_b_balance: pool.token_b_balance,
+            total_shares: pool.total_shares + MIN_LOCKED_SHARES,
+            shares: pool.shares,
+            creator_id: env::current_account_id(),
+            created_at: env::block_timestamp(),
+            creator_fee: 0,
+            price_a_cumulative: U256::zero(),
+            price_b_cumulative: U256::zero(),
+            oracle_updated_at: oracle::block_timestamp_sec(),
+            reserved_a: 0,
+            reserved_b: 0,
+            pricing_mode: PricingMode::default(),
+        };
+        self.internal_update_oracle(pool_key, &mut pool);
+        // the first deployment took any token, the tokens of its pools stay depositable
+        self.whitelisted_tokens.insert(&pool.token_a);
+        self.whitelisted_tokens.insert(&pool.token_b);
+        self.pools.insert(pool_key, &pool);
+    }
+}
+
+fn read_state<T: BorshDeserialize>() -> T {
+    env::state_read().or_panic(ContractError::StateNotFound)
+}
+
+fn state_version_key() -> Vec<u8> {
+    StorageKey::StateVersion.into_storage_key()
+}
+
+pub fn read_state_version() -> u32 {
+    env::storage_read(&state_version_key())
+        .map(|value| u32::from_le_bytes(value.try_into().expect("Wrong state version length")))
+        .unwrap_or(0)
+}
+
+pub fn write_state_version() {
+    env::storage_write(&state_version_key(), &STATE_VERSION.to_le_bytes());
+}
+
+#[near_bindgen]
+impl Contract {
+    // Upgrade the stored state to the current layout, called after the new code is deployed.
+    // Does nothing if the state is already current
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let version = read_state_version();
+        let contract = VersionedContract::read().migrate();
+        write_state_version();
+        log!(
+            "State migrated from version {} to {}",
+            version,
+            STATE_VERSION
+        );
+        contract
+    }
+
+    pub fn get_state_version(&self) -> u32 {
+        read_state_version()
+    }
+
+    // Move up to limit version 0 pools to the current layout, returns the number of pools left.
+    // Anyone can call it, the pools can't be used until they are migrated
+    pub fn migrate_pools(&mut self, limit: u64) -> u64 {
+        let pool_keys: Vec<String> = self.pools_v0.keys().take(limit as usize).collect();
+        for pool_key in pool_keys {
+            let pool = self.pools_v0.remove(&pool_key).unwrap();
+            self.internal_migrate_pool(&pool_key, pool);
+        }
+        self.pools_v0.len()
+    }
+
+    pub fn get_deposits_v0(&self, account_id: AccountId) -> Vec<(AccountId, U128)> {
+        self.deposits_v0
+            .get(&account_id)
+            .map(|deposits| {
+                deposits
+                    .tokens
+                    .iter()
+                    .map(|(token_id, amount)| (token_id, U128(amount)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Accounts with version 0 deposits that aren't reconciled yet
+    pub fn get_accounts_v0(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        self.deposits_v0
+            .keys()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    // Drop the version 0 deposits of the accounts and credit the balances checked against the
+    // token transfers of the first deployment to their deposits. Returns the number of accounts
+    // left to reconcile
+    #[private]
+    pub fn reconcile_deposits_v0(
+        &mut self,
+        accounts: Vec<(AccountId, Vec<(AccountId, U128)>)>,
+    ) -> u64 {
+        for (account_id, balances) in accounts {
+            let mut deposits = self
+                .deposits_v0
+                .remove(&account_id)
+                .or_panic(ContractError::DepositsV0NotFound);
+            deposits.tokens.clear();
+            for (token_id, amount) in balances {
+                if amount.0 > 0 {
+                    self.internal_deposit(&account_id, &token_id, amount.0);
+                }
+            }
+            log!("Version 0 deposits of {} are reconciled", account_id);
+        }
+        self.deposits_v0.len()
+    }
+}
@@ -0,0 +1,214 @@
+use crate::events::u256_dec_format;
+use crate::*;
+
+// Prices are the amount of the other token for one token, scaled by PRICE_PRECISION
+pub const PRICE_PRECISION: Balance = 1_000_000_000_000_000_000;
+// Number of observations kept per pool
+pub const OBSERVATIONS_CAPACITY: u32 = 64;
+
+// Cumulative prices of a pool at the given block timestamp in seconds
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone, Copy)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct Observation {
+    pub timestamp: u64,
+    #[serde(with = "u256_dec_format")]
+    pub price_a_cumulative: U256,
+    #[serde(with = "u256_dec_format")]
+    pub price_b_cumulative: U256,
+}
+
+// Ring buffer of observations. Every observation has its own storage slot, so an update
+// writes one slot and not the whole buffer. `next` is the slot that is written next
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct Observations {
+    slots: LookupMap<u32, Observation>,
+    len: u32,
+    next: u32,
+}
+
+impl Observations {
+    pub fn new(pool_key: &str) -> Self {
+        Self {
+            slots: LookupMap::new(StorageKey::ObservationSlots {
+                pool_key: pool_key.to_string(),
+            }),
+            len: 0,
+            next: 0,
+        }
+    }
+
+    fn get(&self, slot: u32) -> Observation {
+        self.slots
+            .get(&slot)
+            .or_panic(ContractError::NotEnoughObservations)
+    }
+
+    // Observations from the oldest to the latest
+    fn iter(&self) -> impl Iterator<Item = Observation> + '_ {
+        let oldest = if self.len < OBSERVATIONS_CAPACITY {
+            0
+        } else {
+            self.next
+        };
+        (0..self.len).map(move |i| self.get((oldest + i) % OBSERVATIONS_CAPACITY))
+    }
+
+    // Keep one observation per timestamp, the latest one wins
+    fn push(&mut self, observation: Observation) {
+        if self.len > 0 {
+            let last = (self.next + OBSERVATIONS_CAPACITY - 1) % OBSERVATIONS_CAPACITY;
+            if self.get(last).timestamp == observation.timestamp {
+                self.slots.insert(&last, &observation);
+                return;
+            }
+        }
+        self.slots.insert(&self.next, &observation);
+        self.next = (self.next + 1) % OBSERVATIONS_CAPACITY;
+        self.len = std::cmp::min(self.len + 1, OBSERVATIONS_CAPACITY);
+    }
+
+    // Cumulative prices at the timestamp, interpolated between the observations around it.
+    // `current` is the state of the pool now, after the latest observation
+    fn cumulative_prices_at(&self, timestamp: u64, current: Observation) -> Option<(U256, U256)> {
+        let mut before = None;
+        let mut after = current;
+        for observation in self.iter() {
+            if observation.timestamp <= timestamp {
+                before = Some(observation);
+            } else {
+                after = observation;
+                break;
+            }
+        }
+        let before = before?;
+        if before.timestamp == timestamp {
+            return Some((before.price_a_cumulative, before.price_b_cumulative));
+        }
+
+        // prices are constant between two observations, so the accumulators grow linearly
+        let elapsed = U256::from(timestamp - before.timestamp);
+        let interval = U256::from(after.timestamp - before.timestamp);
+        let interpolate = |from: U256, to: U256| {
+            from.overflowing_add(to.overflowing_sub(from).0.overflowing_mul(elapsed).0 / interval)
+                .0
+        };
+        Some((
+            interpolate(before.price_a_cumulative, after.price_a_cumulative),
+            interpolate(before.price_b_cumulative, after.price_b_cumulative),
+        ))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TwapView {
+    pub token_a: AccountId,
+    pub token_b: AccountId,
+    // Average price of token_a in token_b and of token_b in token_a, scaled by PRICE_PRECISION
+    #[serde(with = "u256_dec_format")]
+    pub price_a: U256,
+    #[serde(with = "u256_dec_format")]
+    pub price_b: U256,
+}
+
+pub fn block_timestamp_sec() -> u64 {
+    env::block_timestamp() / 1_000_000_000
+}
+
+impl Pool {
+    // Spot prices of token_a and token_b
+    fn spot_prices(&self) -> (U256, U256) {
+        (
+            math::price(self.token_a_balance, self.token_b_balance, PRICE_PRECISION),
+            math::price(self.token_b_balance, self.token_a_balance, PRICE_PRECISION),
+        )
+    }
+
+    // Cumulative prices at the timestamp, the reserves didn't change since the last update.
+    // Accumulators are U256, so a spot price above u128::MAX is accumulated as is, they wrap
+    // around and only their differences are meaningful
+    fn cumulative_prices(&self, timestamp: u64) -> (U256, U256) {
+        let elapsed = U256::from(timestamp.saturating_sub(self.oracle_updated_at));
+        let (price_a, price_b) = self.spot_prices();
+        (
+            self.price_a_cumulative
+                .overflowing_add(price_a.overflowing_mul(elapsed).0)
+                .0,
+            self.price_b_cumulative
+                .overflowing_add(price_b.overflowing_mul(elapsed).0)
+                .0,
+        )
+    }
+}
+
+impl Contract {
+    // Accumulate prices up to the current block and record an observation.
+    // Must be called before the pool reserves change
+    pub fn internal_update_oracle(&mut self, pool_key: &String, pool: &mut Pool) {
+        if self.is_pool_locked(pool_key) {
+            return;
+        }
+        let timestamp = block_timestamp_sec();
+        let (price_a_cumulative, price_b_cumulative) = pool.cumulative_prices(timestamp);
+        pool.price_a_cumulative = price_a_cumulative;
+        pool.price_b_cumulative = price_b_cumulative;
+        pool.oracle_updated_at = timestamp;
+
+        let mut observations = self
+            .observations
+            .get(pool_key)
+            .unwrap_or_else(|| Observations::new(pool_key));
+        observations.push(Observation {
+            timestamp,
+            price_a_cumulative,
+            price_b_cumulative,
+        });
+        self.observations.insert(pool_key, &observations);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Time-weighted average prices of the pool over exactly the last window_sec seconds.
+    // The accumulators at the start of the window are interpolated between observations
+    pub fn get_twap(&self, pool_id: String, window_sec: u64) -> TwapView {
+        require(window_sec > 0, ContractError::InvalidTwapWindow);
+        let pool = self
+            .pools
+            .get(&pool_id)
+            .or_panic(ContractError::PoolNotFound);
+
+        let now = block_timestamp_sec();
+        let (price_a_cumulative, price_b_cumulative) = pool.cumulative_prices(now);
+        let current = Observation {
+            timestamp: now,
+            price_a_cumulative,
+            price_b_cumulative,
+        };
+        let (price_a_start, price_b_start) = now
+            .checked_sub(window_sec)
+            .and_then(|start| {
+                self.observations
+                    .get(&pool_id)?
+                    .cumulative_prices_at(start, current)
+            })
+            .or_panic(ContractError::NotEnoughObservations);
+
+        let window = U256::from(window_sec);
+        TwapView {
+            token_a: pool.token_a,
+            token_b: pool.token_b,
+            price_a: price_a_cumulative.overflowing_sub(price_a_start).0 / window,
+            price_b: price_b_cumulative.overflowing_sub(price_b_start).0 / window,
+        }
+    }
+
+    pub fn get_observations(&self, pool_id: String) -> Vec<Observation> {
+        self.observations
+            .get(&pool_id)
+            .map(|observations| observations.iter().collect())
+            .unwrap_or_default()
+    }
+}
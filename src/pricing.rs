@@ -0,0 +1,116 @@
+use crate::*;
+
+// How swaps of a pool are priced
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum PricingMode {
+    // The agent resolves every swap, swaps it doesn't resolve in time are refunded
+    #[default]
+    AgentOnly,
+    // Swaps the agent doesn't resolve in time settle at the on-chain constant product price
+    AgentWithFallback,
+    // Swaps settle right away at the on-chain constant product price, the agent isn't called
+    OnChainOnly,
+}
+
+// Swap waiting for the agent, queued in the open batch of its pool, or settled right away
+// with the output amount (None if refunded)
+pub enum SwapResult {
+    Pending(PromiseIndex),
+    Queued,
+    Settled(Option<U128>),
+}
+
+impl Contract {
+    // Send the swap to the agent or settle it on-chain, depending on the pricing mode of the pool
+    pub fn internal_request_swap(&mut self, request: SwapRequest) -> SwapResult {
+        let pool = self
+            .pools
+            .get(&get_pool_key(&request.token_in, &request.token_out))
+            .or_panic(ContractError::PoolNotFound);
+
+        match pool.pricing_mode {
+            PricingMode::OnChainOnly => {
+                // the agent isn't called, its payment goes back to the sender
+                self.internal_refund_agent_payment(&request);
+                self.internal_withdraw_deposit(
+                    &request.sender_id,
+                    &request.token_in,
+                    request.amount_in.0,
+                );
+                SwapResult::Settled(self.internal_settle_swap_on_chain(request))
+            }
+            PricingMode::AgentOnly | PricingMode::AgentWithFallback => {
+                self.run_agent_market_maker(request)
+            }
+        }
+    }
+
+    // Settle the swap at the on-chain constant product price after fees. amount_in is returned
+    // to deposits if the output is below min_amount_out or the pool can't take the swap, so it
+    // never panics after the yield of a timed out swap
+    pub fn internal_settle_swap_on_chain(&mut self, request: SwapRequest) -> Option<U128> {
+        let pool_key = get_pool_key(&request.token_in, &request.token_out);
+        let pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
+        if self.is_pool_locked(&pool_key) {
+            log!(
+                "{}, amount_in is returned to deposits",
+                ContractError::PoolLocked
+            );
+            self.internal_deposit(&request.sender_id, &request.token_in, request.amount_in.0);
+            return None;
+        }
+
+        let amount_in = self.swap_amount_after_fees(
+            &request.token_in,
+            &request.token_out,
+            request.amount_in.0,
+            &request.referral_id,
+        );
+        let (balance_in, balance_out) = pool.balances(&request.token_in);
+        let amount_out = match math::get_amount_out(amount_in, balance_in, balance_out) {
+            Ok(amount_out) => amount_out,
+            Err(error) => {
+                log!(
+                    "{}, amount_in is returned to deposits",
+                    ContractError::from(error)
+                );
+                self.internal_deposit(&request.sender_id, &request.token_in, request.amount_in.0);
+                return None;
+            }
+        };
+        log!("On-chain price output amount: {}", amount_out);
+
+        self.internal_settle_swap(request, amount_out, (0, 0))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn set_pricing_mode(
+        &mut self,
+        token_a: AccountId,
+        token_b: AccountId,
+        pricing_mode: PricingMode,
+    ) {
+        let pool_key = get_pool_key(&token_a, &token_b);
+        let mut pool = self
+            .pools
+            .get(&pool_key)
+            .or_panic(ContractError::PoolNotFound);
+        pool.pricing_mode = pricing_mode;
+        self.pools.insert(&pool_key, &pool);
+        log!(
+            "Pricing mode of {} is set to {}",
+            pool_key,
+            json!(pricing_mode)
+        );
+    }
+}
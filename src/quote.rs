@@ -0,0 +1,108 @@
+use crate::*;
+
+// Firm quote issued by the agent off-chain (RFQ mode). The agent signs
+// sha256(borsh(contract_id, quote)) with the ed25519 key registered in the contract.
+#[derive(BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapQuote {
+    pub sender_id: AccountId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: U128,
+    pub amount_out: U128,
+    // Must be greater than the last nonce used by sender_id
+    pub nonce: U64,
+    // Block timestamp in nanoseconds after which the quote is rejected
+    pub expires_at: U64,
+}
+
+impl SwapQuote {
+    pub fn message(&self) -> Vec<u8> {
+        let payload = near_sdk::borsh::to_vec(&(env::current_account_id(), self))
+            .expect("Failed to serialize quote");
+        env::sha256(&payload)
+    }
+}
+
+impl Contract {
+    // Verify the quote signed by the agent and settle the swap in the same transaction
+    pub fn internal_swap_with_quote(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        amount_in: Balance,
+        quote: SwapQuote,
+        signature: Base64VecU8,
+    ) -> Balance {
+        require(
+            &quote.sender_id == sender_id,
+            ContractError::QuoteSenderMismatch,
+        );
+        require(
+            &quote.token_in == token_in,
+            ContractError::QuoteTokenMismatch,
+        );
+        require(
+            quote.amount_in.0 == amount_in,
+            ContractError::QuoteAmountMismatch,
+        );
+        require(
+            env::block_timestamp() <= quote.expires_at.0,
+            ContractError::QuoteExpired,
+        );
+
+        let last_nonce = self.quote_nonces.get(sender_id).unwrap_or(0);
+        require(quote.nonce.0 > last_nonce, ContractError::QuoteNonceUsed);
+
+        self.assert_agent_signature(&quote.message(), &signature);
+        self.quote_nonces.insert(sender_id, &quote.nonce.0);
+        self.internal_withdraw_deposit(sender_id, token_in, amount_in);
+        let amount_in = self.internal_take_swap_fees(token_in, &quote.token_out, amount_in, &None);
+
+        let amount_out =
+            self.internal_swap(token_in, &quote.token_out, amount_in, quote.amount_out.0);
+        self.internal_send_tokens(
+            sender_id,
+            sender_id.clone(),
+            quote.token_out,
+            amount_out,
+            None,
+        );
+
+        log!("Quote settled. Amount_out: {}", amount_out);
+        amount_out
+    }
+
+    fn assert_agent_signature(&self, message: &[u8], signature: &Base64VecU8) {
+        let public_key = self
+            .agent_public_key
+            .as_ref()
+            .or_panic(ContractError::AgentPublicKeyNotSet);
+        let public_key: &[u8; 32] = public_key.as_bytes()[1..]
+            .try_into()
+            .unwrap_or_else(|_| ContractError::InvalidPublicKey.panic());
+        let signature: &[u8; 64] = signature
+            .0
+            .as_slice()
+            .try_into()
+            .unwrap_or_else(|_| ContractError::InvalidSignature.panic());
+
+        require(
+            env::ed25519_verify(signature, message, public_key),
+            ContractError::InvalidSignature,
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Message the agent has to sign for the given quote
+    pub fn get_quote_message(&self, quote: SwapQuote) -> Base64VecU8 {
+        Base64VecU8(quote.message())
+    }
+
+    pub fn get_quote_nonce(&self, account_id: AccountId) -> U64 {
+        U64(self.quote_nonces.get(&account_id).unwrap_or(0))
+    }
+}
@@ -0,0 +1,286 @@
+use crate::events::u128_dec_format;
+use crate::*;
+use std::collections::HashMap;
+
+pub const DEFAULT_UNBONDING_PERIOD_SEC: u64 = 7 * 24 * 60 * 60;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum BondAsset {
+    Near,
+    Token { token_id: AccountId },
+}
+
+// Unbonded amount that can be withdrawn after available_at, block timestamp in seconds.
+// It can still be slashed until then
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct Unbonding {
+    pub asset: BondAsset,
+    #[serde(with = "u128_dec_format")]
+    pub amount: Balance,
+    pub available_at: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct AgentBond {
+    near: Balance,
+    tokens: HashMap<AccountId, Balance>,
+    unbonding: Vec<Unbonding>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AgentBondView {
+    pub near: U128,
+    pub tokens: Vec<(AccountId, U128)>,
+    pub unbonding: Vec<Unbonding>,
+}
+
+// Difference between the agent amount_out and the on-chain price beyond the tolerance
+pub struct QuoteDeviation {
+    pub amount: Balance,
+    pub underpaid: bool,
+}
+
+impl AgentBond {
+    fn bonded(&mut self, asset: &BondAsset) -> &mut Balance {
+        match asset {
+            BondAsset::Near => &mut self.near,
+            BondAsset::Token { token_id } => self.tokens.entry(token_id.clone()).or_insert(0),
+        }
+    }
+
+    // Take up to amount of the asset, from the bonded amount first and then from unbonding.
+    // Returns the amount taken
+    fn slash(&mut self, asset: &BondAsset, amount: Balance) -> Balance {
+        let bonded = self.bonded(asset);
+        let mut taken = std::cmp::min(*bonded, amount);
+        *bonded -= taken;
+
+        for unbonding in self.unbonding.iter_mut() {
+            if &unbonding.asset == asset && taken < amount {
+                let part = std::cmp::min(unbonding.amount, amount - taken);
+                unbonding.amount -= part;
+                taken += part;
+            }
+        }
+        self.unbonding.retain(|unbonding| unbonding.amount > 0);
+        taken
+    }
+}
+
+impl Contract {
+    fn internal_bond(&mut self, account_id: &AccountId, asset: BondAsset, amount: Balance) {
+        require(amount > 0, ContractError::ZeroAmount);
+        let mut bond = self.agent_bonds.get(account_id).unwrap_or_default();
+        let bonded = bond.bonded(&asset);
+        *bonded = math::add(*bonded, amount).unwrap_or_panic();
+        self.agent_bonds.insert(account_id, &bond);
+    }
+
+    // Output of the swap at the on-chain constant product price after the creator, referral and
+    // agent fees are taken from amount_in, as the agent is expected to quote it
+    pub fn on_chain_amount_out(
+        &self,
+        request: &SwapRequest,
+        agent_fee_in: Balance,
+    ) -> Result<Balance, ContractError> {
+        let pool = self
+            .pools
+            .get(&get_pool_key(&request.token_in, &request.token_out))
+            .ok_or(ContractError::PoolNotFound)?;
+        let amount_in = self.swap_amount_after_fees(
+            &request.token_in,
+            &request.token_out,
+            request.amount_in.0,
+            &request.referral_id,
+        ) - agent_fee_in;
+        let (balance_in, balance_out) = pool.balances(&request.token_in);
+        Ok(math::get_amount_out(amount_in, balance_in, balance_out)?)
+    }
+
+    // Compare the agent amount_out of a swap with the on-chain price after fees,
+    // before the swap is settled. None if it's within the agent_tolerance of the config or the
+    // guard is off
+    pub fn internal_check_agent_quote(
+        &self,
+        request: &SwapRequest,
+        amount_out: Balance,
+    ) -> Option<QuoteDeviation> {
+        let tolerance = self.config.agent_tolerance?;
+        let pool_key = get_pool_key(&request.token_in, &request.token_out);
+        if amount_out < request.min_amount_out.0 || self.is_pool_locked(&pool_key) {
+            return None;
+        }
+
+        let (agent_fee_in, _) = self.agent_fees(request.amount_in.0, amount_out);
+        // no deviation is measured if there is no on-chain price for the swap
+        let expected = self.on_chain_amount_out(request, agent_fee_in).ok()?;
+        let allowed =
+            math::mul_div_floor(expected, tolerance as Balance, fees::FEE_DIVISOR as Balance)
+                .unwrap_or_panic();
+
+        if amount_out.saturating_add(allowed) < expected {
+            Some(QuoteDeviation {
+                amount: expected - amount_out,
+                underpaid: true,
+            })
+        } else if amount_out > expected.saturating_add(allowed) {
+            Some(QuoteDeviation {
+                amount: amount_out - expected,
+                underpaid: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Slash the agent bond for a settled swap. The deviation is paid from the token_out bond
+    // to the deposits of the receiver, who got the short output, if the agent underpaid, or back
+    // to the pool reserve if it overpaid. near_slash_penalty is sent to the receiver, or kept by
+    // the contract for overpays
+    pub fn internal_slash_agent(&mut self, request: &SwapRequest, deviation: QuoteDeviation) {
+        let agent_account_id = self.agent_account_id.clone();
+        let mut bond = self.agent_bonds.get(&agent_account_id).unwrap_or_default();
+        let amount = bond.slash(
+            &BondAsset::Token {
+                token_id: request.token_out.clone(),
+            },
+            deviation.amount,
+        );
+        let near_penalty = bond.slash(&BondAsset::Near, self.near_slash_penalty);
+        self.agent_bonds.insert(&agent_account_id, &bond);
+
+        log!(
+            "Agent {} {} by {} {}, slashed {} {} and {} yoctoNEAR",
+            agent_account_id,
+            if deviation.underpaid {
+                "underpaid"
+            } else {
+                "overpaid"
+            },
+            deviation.amount,
+            request.token_out,
+            amount,
+            request.token_out,
+            near_penalty
+        );
+
+        if deviation.underpaid {
+            let receiver_id = request.receiver_id.as_ref().unwrap_or(&request.sender_id);
+            if amount > 0 {
+                self.internal_deposit(receiver_id, &request.token_out, amount);
+            }
+            if near_penalty > 0 {
+                Promise::new(receiver_id.clone())
+                    .transfer(NearToken::from_yoctonear(near_penalty))
+                    .detach();
+            }
+        } else if amount > 0 {
+            let pool_key = get_pool_key(&request.token_in, &request.token_out);
+            let mut pool = self
+                .pools
+                .get(&pool_key)
+                .or_panic(ContractError::PoolNotFound);
+            self.internal_update_oracle(&pool_key, &mut pool);
+            pool.add_reserve(&request.token_out, amount);
+            self.pools.insert(&pool_key, &pool);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Bond the attached NEAR
+    #[payable]
+    pub fn bond_near(&mut self) {
+        self.internal_bond(
+            &env::predecessor_account_id(),
+            BondAsset::Near,
+            env::attached_deposit().as_yoctonear(),
+        );
+    }
+
+    // Bond tokens from the deposits of the caller
+    pub fn bond_tokens(&mut self, token_id: AccountId, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        self.internal_withdraw_deposit(&account_id, &token_id, amount.0);
+        self.internal_bond(&account_id, BondAsset::Token { token_id }, amount.0);
+    }
+
+    // Start unbonding, the amount can be withdrawn after the unbonding period
+    pub fn unbond(&mut self, asset: BondAsset, amount: U128) {
+        require(amount.0 > 0, ContractError::ZeroAmount);
+        let account_id = env::predecessor_account_id();
+        let mut bond = self.agent_bonds.get(&account_id).unwrap_or_default();
+        let bonded = bond.bonded(&asset);
+        require(
+            *bonded >= amount.0,
+            ContractError::InsufficientBond { bonded: *bonded },
+        );
+        *bonded -= amount.0;
+        bond.unbonding.push(Unbonding {
+            asset,
+            amount: amount.0,
+            available_at: oracle::block_timestamp_sec() + self.unbonding_period_sec,
+        });
+        self.agent_bonds.insert(&account_id, &bond);
+    }
+
+    // Withdraw all unbonded amounts that are available. NEAR is sent to the caller,
+    // tokens go to the caller's deposits
+    pub fn withdraw_unbonded(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let mut bond = self.agent_bonds.get(&account_id).unwrap_or_default();
+        let now = oracle::block_timestamp_sec();
+        let (available, unbonding): (Vec<Unbonding>, Vec<Unbonding>) = bond
+            .unbonding
+            .into_iter()
+            .partition(|unbonding| unbonding.available_at <= now);
+        require(!available.is_empty(), ContractError::NothingToWithdraw);
+        bond.unbonding = unbonding;
+        self.agent_bonds.insert(&account_id, &bond);
+
+        for unbonding in available {
+            match unbonding.asset {
+                BondAsset::Near => Promise::new(account_id.clone())
+                    .transfer(NearToken::from_yoctonear(unbonding.amount))
+                    .detach(),
+                BondAsset::Token { token_id } => {
+                    self.internal_deposit(&account_id, &token_id, unbonding.amount)
+                }
+            }
+        }
+    }
+
+    pub fn get_agent_bond(&self, account_id: AccountId) -> AgentBondView {
+        let bond = self.agent_bonds.get(&account_id).unwrap_or_default();
+        let mut tokens: Vec<(AccountId, U128)> = bond
+            .tokens
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect();
+        tokens.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        AgentBondView {
+            near: U128(bond.near),
+            tokens,
+            unbonding: bond.unbonding,
+        }
+    }
+
+    #[private]
+    pub fn set_unbonding_period(&mut self, unbonding_period_sec: u64) {
+        self.unbonding_period_sec = unbonding_period_sec;
+    }
+
+    // NEAR slashed from the agent bond for every quote outside the tolerance
+    #[private]
+    pub fn set_near_slash_penalty(&mut self, near_slash_penalty: U128) {
+        self.near_slash_penalty = near_slash_penalty.0;
+    }
+}
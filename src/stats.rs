@@ -0,0 +1,83 @@
+use crate::*;
+
+// Counters of the swap requests sent to an agent
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct AgentStats {
+    requests: u64,
+    resolved: u64,
+    timed_out: u64,
+    rejected: u64,
+    // Sum of the blocks between the requests and the agent responses
+    total_latency_blocks: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AgentStatsView {
+    pub agent_account_id: AccountId,
+    pub requests: u64,
+    pub resolved: u64,
+    pub timed_out: u64,
+    // Responses with amount_out that failed the on-chain checks
+    pub rejected: u64,
+    // Requests without a response yet
+    pub pending: u64,
+    pub average_latency_blocks: u64,
+}
+
+impl Contract {
+    fn internal_update_agent_stats(&mut self, update: impl FnOnce(&mut AgentStats)) {
+        let mut stats = self
+            .agent_stats
+            .get(&self.agent_account_id)
+            .unwrap_or_default();
+        update(&mut stats);
+        self.agent_stats.insert(&self.agent_account_id, &stats);
+    }
+
+    pub fn internal_record_agent_request(&mut self) {
+        self.internal_update_agent_stats(|stats| stats.requests += 1);
+    }
+
+    // Latency is counted from the block of the request to the block of the response
+    pub fn internal_record_agent_response(&mut self, requested_at: u64) {
+        let latency = env::block_height().saturating_sub(requested_at);
+        self.internal_update_agent_stats(|stats| {
+            stats.resolved += 1;
+            stats.total_latency_blocks += latency;
+        });
+    }
+
+    pub fn internal_record_agent_timeout(&mut self) {
+        self.internal_update_agent_stats(|stats| stats.timed_out += 1);
+    }
+
+    pub fn internal_record_agent_rejection(&mut self) {
+        self.internal_update_agent_stats(|stats| stats.rejected += 1);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Stats of the given agent account, the current agent if omitted
+    pub fn get_agent_stats(&self, agent_account_id: Option<AccountId>) -> AgentStatsView {
+        let agent_account_id = agent_account_id.unwrap_or_else(|| self.agent_account_id.clone());
+        let stats = self.agent_stats.get(&agent_account_id).unwrap_or_default();
+
+        AgentStatsView {
+            agent_account_id,
+            requests: stats.requests,
+            resolved: stats.resolved,
+            timed_out: stats.timed_out,
+            rejected: stats.rejected,
+            pending: stats
+                .requests
+                .saturating_sub(stats.resolved + stats.timed_out),
+            average_latency_blocks: stats
+                .total_latency_blocks
+                .checked_div(stats.resolved)
+                .unwrap_or(0),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::*;
+
+// NEAR an account deposited for the storage of its pending swaps. `locked` is held by the
+// swaps waiting for the agent and is released when they are settled
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct StorageBalance {
+    pub total: Balance,
+    pub locked: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceView {
+    pub total: U128,
+    pub available: U128,
+}
+
+impl From<&StorageBalance> for StorageBalanceView {
+    fn from(balance: &StorageBalance) -> Self {
+        StorageBalanceView {
+            total: U128(balance.total),
+            available: U128(balance.total - balance.locked),
+        }
+    }
+}
+
+// Cost of the storage added since initial_storage_usage
+pub fn storage_cost(initial_storage_usage: u64) -> Balance {
+    let added = env::storage_usage().saturating_sub(initial_storage_usage);
+    env::storage_byte_cost().as_yoctonear() * added as Balance
+}
+
+// Pay the storage added by the call from the attached deposit, the rest goes back to the caller
+pub fn charge_attached_storage(initial_storage_usage: u64) {
+    let cost = storage_cost(initial_storage_usage);
+    let attached = env::attached_deposit().as_yoctonear();
+    require(
+        attached >= cost,
+        ContractError::InsufficientStorageDeposit { required: cost },
+    );
+    send_near(&env::predecessor_account_id(), attached - cost);
+}
+
+pub fn send_near(account_id: &AccountId, amount: Balance) {
+    if amount > 0 {
+        Promise::new(account_id.clone())
+            .transfer(NearToken::from_yoctonear(amount))
+            .detach();
+    }
+}
+
+impl Contract {
+    pub fn internal_lock_storage(&mut self, account_id: &AccountId, amount: Balance) {
+        let mut balance = self.storage_balances.get(account_id).unwrap_or_default();
+        require(
+            balance.total - balance.locked >= amount,
+            ContractError::InsufficientStorageDeposit { required: amount },
+        );
+        balance.locked += amount;
+        self.storage_balances.insert(account_id, &balance);
+    }
+
+    pub fn internal_unlock_storage(&mut self, account_id: &AccountId, amount: Balance) {
+        if let Some(mut balance) = self.storage_balances.get(account_id) {
+            balance.locked = balance.locked.saturating_sub(amount);
+            self.storage_balances.insert(account_id, &balance);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Deposit the attached NEAR for the storage of the pending swaps of account_id,
+    // the caller if omitted
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) -> StorageBalanceView {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let amount = env::attached_deposit().as_yoctonear();
+        require(amount > 0, ContractError::ZeroAmount);
+
+        let mut balance = self.storage_balances.get(&account_id).unwrap_or_default();
+        balance.total = math::add(balance.total, amount).unwrap_or_panic();
+        self.storage_balances.insert(&account_id, &balance);
+        StorageBalanceView::from(&balance)
+    }
+
+    // Withdraw the storage deposit that isn't locked by pending swaps, all of it if amount is
+    // omitted
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalanceView {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut balance = self.storage_balances.get(&account_id).unwrap_or_default();
+        let available = balance.total - balance.locked;
+        let amount = amount.map(|amount| amount.0).unwrap_or(available);
+        require(amount > 0, ContractError::NothingToWithdraw);
+        require(
+            amount <= available,
+            ContractError::InsufficientStorageDeposit { required: amount },
+        );
+
+        balance.total -= amount;
+        if balance.total == 0 {
+            self.storage_balances.remove(&account_id);
+        } else {
+            self.storage_balances.insert(&account_id, &balance);
+        }
+        send_near(&account_id, amount);
+        StorageBalanceView::from(&balance)
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> StorageBalanceView {
+        StorageBalanceView::from(&self.storage_balances.get(&account_id).unwrap_or_default())
+    }
+}
@@ -0,0 +1,151 @@
+use crate::*;
+
+// Interface for contracts that swap through the AMM. The caller deposits token_in with
+// `ft_transfer_call` and a `Deposit` action first, then calls `swap`. The promise returned
+// by `swap` resolves to the output amount, or to null if the swap was rejected and
+// amount_in went back to the caller's deposits. A swap that joins the open batch of its pool
+// resolves to null right away, it settles when the agent resolves the batch.
+#[ext_contract(ext_amm)]
+pub trait Amm {
+    fn swap(
+        &mut self,
+        token_in: AccountId,
+        token_out: AccountId,
+        amount_in: U128,
+        min_amount_out: U128,
+        receiver_id: Option<AccountId>,
+        msg: Option<String>,
+    ) -> Option<U128>;
+    fn get_swap_balances(&self, token_in: AccountId, token_out: AccountId) -> (U128, U128);
+    fn get_user_deposits(&self, account_id: AccountId) -> Vec<(AccountId, U128)>;
+    fn withdraw(&mut self, token_id: AccountId, amount: Option<U128>) -> Promise;
+}
+
+impl Contract {
+    // Gas the yielded swap callback needs on top of response_gas to send the output
+    pub fn send_tokens_gas(&self, msg: &Option<String>) -> Gas {
+        match msg {
+            Some(_) => self
+                .config
+                .ft_transfer_call_gas
+                .saturating_add(self.config.resolve_transfer_gas),
+            None => self.config.resolve_transfer_gas,
+        }
+    }
+
+    // Send the swap output to the receiver, with `ft_transfer_call` if msg is given.
+    // Whatever the transfer doesn't deliver goes back to the deposits of sender_id
+    pub fn internal_send_tokens(
+        &self,
+        sender_id: &AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        msg: Option<String>,
+    ) {
+        let transfer =
+            ext_ft::ext(token_id.clone()).with_attached_deposit(NearToken::from_yoctonear(1));
+        let callback =
+            Self::ext(env::current_account_id()).with_static_gas(self.config.resolve_transfer_gas);
+
+        match msg {
+            Some(msg) => transfer
+                .with_static_gas(self.config.ft_transfer_call_gas)
+                .ft_transfer_call(
+                    receiver_id,
+                    U128(amount),
+                    Some("Swap completed".to_string()),
+                    msg,
+                )
+                .then(callback.on_send_tokens_call(sender_id.clone(), token_id, U128(amount))),
+            None => transfer
+                .with_static_gas(self.config.ft_transfer_gas)
+                .ft_transfer(
+                    receiver_id,
+                    U128(amount),
+                    Some("Swap completed".to_string()),
+                )
+                .then(callback.on_send_tokens(sender_id.clone(), token_id, U128(amount))),
+        }
+        .detach();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Swap from the deposits of the caller, the output goes to receiver_id (the caller if omitted).
+    // Pools in the OnChainOnly pricing mode settle the swap in this call.
+    // Attached NEAR pays the agent runner
+    #[payable]
+    pub fn swap(
+        &mut self,
+        token_in: AccountId,
+        token_out: AccountId,
+        amount_in: U128,
+        min_amount_out: U128,
+        receiver_id: Option<AccountId>,
+        msg: Option<String>,
+    ) {
+        require(amount_in.0 > 0, ContractError::ZeroAmount);
+
+        let result = self.internal_request_swap(SwapRequest {
+            sender_id: env::predecessor_account_id(),
+            token_in,
+            token_out,
+            amount_in,
+            min_amount_out,
+            receiver_id,
+            msg,
+            referral_id: None,
+            agent_payment: Some(U128(env::attached_deposit().as_yoctonear())),
+            zap: None,
+        });
+        match result {
+            SwapResult::Pending(promise_idx) => env::promise_return(promise_idx),
+            SwapResult::Queued => env::value_return(b"null"),
+            SwapResult::Settled(amount_out) => {
+                env::value_return(serde_json::to_vec(&amount_out).unwrap())
+            }
+        }
+    }
+
+    // Return the tokens to deposits if the transfer failed
+    #[private]
+    pub fn on_send_tokens(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) {
+        if result.is_err() {
+            log!(
+                "Transfer failed, {} {} returned to deposits",
+                amount.0,
+                token_id
+            );
+            self.internal_deposit(&sender_id, &token_id, amount.0);
+        }
+    }
+
+    // Return the part the receiver refunded to deposits
+    #[private]
+    pub fn on_send_tokens_call(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] used_amount: Result<U128, PromiseError>,
+    ) {
+        let used_amount = used_amount.map(|used| used.0.min(amount.0)).unwrap_or(0);
+        let refund = amount.0 - used_amount;
+        if refund > 0 {
+            log!(
+                "Receiver refunded {} {}, returned to deposits",
+                refund,
+                token_id
+            );
+            self.internal_deposit(&sender_id, &token_id, refund);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::*;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::collections::UnorderedSet;
+
+// Part of the token metadata needed to show human-readable amounts
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl Contract {
+    // Tokens are accepted if they are in the global whitelist or in the whitelist of the sender
+    pub fn is_token_whitelisted(&self, account_id: &AccountId, token_id: &AccountId) -> bool {
+        self.whitelisted_tokens.contains(token_id)
+            || self
+                .user_whitelisted_tokens
+                .get(account_id)
+                .map(|tokens| tokens.contains(token_id))
+                .unwrap_or(false)
+    }
+
+    pub fn assert_token_whitelisted(&self, account_id: &AccountId, token_id: &AccountId) {
+        require(
+            self.is_token_whitelisted(account_id, token_id),
+            ContractError::TokenNotWhitelisted {
+                token_id: token_id.clone(),
+            },
+        );
+    }
+
+    fn get_user_whitelist(&self, account_id: &AccountId) -> UnorderedSet<AccountId> {
+        self.user_whitelisted_tokens
+            .get(account_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::UserWhitelistedTokens {
+                    account_id: account_id.clone(),
+                })
+            })
+    }
+
+    // Cached metadata of the swap tokens for the agent event, null if it wasn't fetched yet
+    pub fn tokens_metadata_json(
+        &self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+    ) -> serde_json::Value {
+        let mut tokens = serde_json::Map::new();
+        for token_id in [token_in, token_out] {
+            tokens.insert(
+                token_id.to_string(),
+                json!(self.token_metadata.get(token_id)),
+            );
+        }
+        tokens.into()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn add_whitelisted_tokens(&mut self, token_ids: Vec<AccountId>) {
+        for token_id in token_ids {
+            self.whitelisted_tokens.insert(&token_id);
+        }
+    }
+
+    #[private]
+    pub fn remove_whitelisted_tokens(&mut self, token_ids: Vec<AccountId>) {
+        for token_id in token_ids {
+            self.whitelisted_tokens.remove(&token_id);
+        }
+    }
+
+    // Allow the caller to deposit tokens that are not in the global whitelist. The storage is
+    // paid from the attached deposit, the rest is refunded
+    #[payable]
+    pub fn register_tokens(&mut self, token_ids: Vec<AccountId>) {
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        let mut tokens = self.get_user_whitelist(&account_id);
+        for token_id in token_ids {
+            tokens.insert(&token_id);
+        }
+        self.user_whitelisted_tokens.insert(&account_id, &tokens);
+        storage::charge_attached_storage(initial_storage_usage);
+    }
+
+    pub fn unregister_tokens(&mut self, token_ids: Vec<AccountId>) {
+        let account_id = env::predecessor_account_id();
+        let mut tokens = self.get_user_whitelist(&account_id);
+        for token_id in token_ids {
+            tokens.remove(&token_id);
+        }
+        self.user_whitelisted_tokens.insert(&account_id, &tokens);
+    }
+
+    pub fn get_whitelisted_tokens(&self) -> Vec<AccountId> {
+        self.whitelisted_tokens.to_vec()
+    }
+
+    pub fn get_user_whitelisted_tokens(&self, account_id: AccountId) -> Vec<AccountId> {
+        self.user_whitelisted_tokens
+            .get(&account_id)
+            .map(|tokens| tokens.to_vec())
+            .unwrap_or_default()
+    }
+
+    // Fetch `ft_metadata` of a whitelisted token and cache its symbol and decimals. The storage
+    // is paid from the attached deposit, the rest is refunded
+    #[payable]
+    pub fn cache_token_metadata(&mut self, token_id: AccountId) -> Promise {
+        require(
+            self.is_token_whitelisted(&env::predecessor_account_id(), &token_id),
+            ContractError::TokenNotWhitelisted {
+                token_id: token_id.clone(),
+            },
+        );
+
+        ext_ft::ext(token_id.clone())
+            .with_static_gas(self.config.ft_metadata_gas)
+            .ft_metadata()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(self.config.resolve_metadata_gas)
+                    .on_ft_metadata(
+                        token_id,
+                        env::predecessor_account_id(),
+                        U128(env::attached_deposit().as_yoctonear()),
+                    ),
+            )
+    }
+
+    #[private]
+    pub fn on_ft_metadata(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        deposit: U128,
+        #[callback_result] metadata: Result<FungibleTokenMetadata, PromiseError>,
+    ) -> Option<TokenMetadata> {
+        let Ok(metadata) = metadata else {
+            log!("Failed to fetch metadata of {}", token_id);
+            storage::send_near(&account_id, deposit.0);
+            return None;
+        };
+
+        let initial_storage_usage = env::storage_usage();
+        let metadata = TokenMetadata {
+            symbol: metadata.symbol,
+            decimals: metadata.decimals,
+        };
+        let previous = self.token_metadata.insert(&token_id, &metadata);
+        let cost = storage::storage_cost(initial_storage_usage);
+        if cost > deposit.0 {
+            log!(
+                "Storage deposit of {} yoctoNEAR is required to cache metadata of {}",
+                cost,
+                token_id
+            );
+            match previous {
+                Some(previous) => self.token_metadata.insert(&token_id, &previous),
+                None => self.token_metadata.remove(&token_id),
+            };
+            storage::send_near(&account_id, deposit.0);
+            return None;
+        }
+        storage::send_near(&account_id, deposit.0 - cost);
+        Some(metadata)
+    }
+
+    pub fn get_token_metadata(&self, token_id: AccountId) -> Option<TokenMetadata> {
+        self.token_metadata.get(&token_id)
+    }
+
+    pub fn get_tokens_metadata(
+        &self,
+        token_ids: Vec<AccountId>,
+    ) -> Vec<(AccountId, Option<TokenMetadata>)> {
+        token_ids
+            .into_iter()
+            .map(|token_id| {
+                let metadata = self.token_metadata.get(&token_id);
+                (token_id, metadata)
+            })
+            .collect()
+    }
+}
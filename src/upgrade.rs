@@ -0,0 +1,207 @@
+use crate::*;
+use near_sdk::collections::LazyOption;
+
+// Gas for `migrate` after the code is deployed, it also gets all the gas left
+const MIN_GAS_FOR_MIGRATE: Gas = Gas::from_tgas(20);
+
+// Who can upgrade the contract besides the contract account, and the time lock of the code.
+// The upgrade settings and the staged code are stored apart from the contract state, so
+// upgrades work whatever the state layout is, even if the deployed code can't read the state
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeConfig {
+    pub dao_id: Option<AccountId>,
+    // Seconds between staging the code and deploying it. If it's set, the code can only be
+    // deployed with `stage_code` and `deploy_staged_code`, so users have time to exit
+    pub time_lock_sec: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+struct StagedCode {
+    code: Vec<u8>,
+    hash: CryptoHash,
+    // Block timestamp in seconds, the code can be deployed after the current time lock from it
+    staged_at: u64,
+}
+
+impl StagedCode {
+    fn available_at(&self) -> u64 {
+        self.staged_at + Contract::get_upgrade_config().time_lock_sec
+    }
+}
+
+// Upgrade config that lowers the time lock, it can be applied after the current time lock
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct StagedUpgradeConfig {
+    pub upgrade_config: UpgradeConfig,
+    // Block timestamp in seconds
+    pub available_at: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StagedCodeView {
+    // sha256 of the code in hex
+    pub hash: String,
+    pub available_at: u64,
+}
+
+fn upgrade_config_storage() -> LazyOption<UpgradeConfig> {
+    LazyOption::new(StorageKey::UpgradeConfig, None)
+}
+
+fn staged_code_storage() -> LazyOption<StagedCode> {
+    LazyOption::new(StorageKey::StagedCode, None)
+}
+
+fn staged_upgrade_config_storage() -> LazyOption<StagedUpgradeConfig> {
+    LazyOption::new(StorageKey::StagedUpgradeConfig, None)
+}
+
+fn assert_upgrade_authority() {
+    let predecessor_id = env::predecessor_account_id();
+    require(
+        predecessor_id == env::current_account_id()
+            || upgrade_config_storage()
+                .get()
+                .and_then(|config| config.dao_id)
+                == Some(predecessor_id),
+        ContractError::NotUpgradeAuthority,
+    );
+}
+
+// The code is passed as the raw input of the call
+fn input_code() -> Vec<u8> {
+    env::input()
+        .filter(|code| !code.is_empty())
+        .or_panic(ContractError::EmptyCode)
+}
+
+fn hex(hash: &CryptoHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Deploy and migrate in one receipt, the code isn't deployed if migrate fails
+fn deploy_and_migrate(code: Vec<u8>) -> Promise {
+    log!("Deploying code {}", hex(&env::sha256_array(&code)));
+    Promise::new(env::current_account_id())
+        .deploy_contract(code)
+        .function_call_weight(
+            "migrate",
+            vec![],
+            NearToken::from_yoctonear(0),
+            MIN_GAS_FOR_MIGRATE,
+            GasWeight(1),
+        )
+}
+
+#[near_bindgen]
+impl Contract {
+    // A config that lowers the time lock is staged behind the current time lock, so it can't
+    // be used to deploy code sooner. Other configs are set right away. Replaces the config
+    // staged before
+    pub fn set_upgrade_config(upgrade_config: UpgradeConfig) {
+        assert_upgrade_authority();
+        let time_lock_sec = Self::get_upgrade_config().time_lock_sec;
+        if upgrade_config.time_lock_sec < time_lock_sec {
+            let available_at = oracle::block_timestamp_sec() + time_lock_sec;
+            log!(
+                "Upgrade config is staged, it can be applied at {}",
+                available_at
+            );
+            staged_upgrade_config_storage().set(&StagedUpgradeConfig {
+                upgrade_config,
+                available_at,
+            });
+        } else {
+            staged_upgrade_config_storage().remove();
+            upgrade_config_storage().set(&upgrade_config);
+        }
+    }
+
+    pub fn apply_staged_upgrade_config() {
+        assert_upgrade_authority();
+        let mut storage = staged_upgrade_config_storage();
+        let staged_config = storage
+            .get()
+            .or_panic(ContractError::StagedUpgradeConfigNotFound);
+        require(
+            oracle::block_timestamp_sec() >= staged_config.available_at,
+            ContractError::StagedUpgradeConfigLocked {
+                available_at: staged_config.available_at,
+            },
+        );
+        storage.remove();
+        upgrade_config_storage().set(&staged_config.upgrade_config);
+    }
+
+    pub fn get_staged_upgrade_config() -> Option<StagedUpgradeConfig> {
+        staged_upgrade_config_storage().get()
+    }
+
+    pub fn get_upgrade_config() -> UpgradeConfig {
+        upgrade_config_storage().get().unwrap_or_default()
+    }
+
+    // Deploy the code from the input and migrate the state. Only without a time lock
+    pub fn upgrade() -> Promise {
+        assert_upgrade_authority();
+        require(
+            Self::get_upgrade_config().time_lock_sec == 0,
+            ContractError::UpgradeTimeLocked,
+        );
+        deploy_and_migrate(input_code())
+    }
+
+    // Stage the code from the input, it can be deployed after the time lock. A time lock raised
+    // meanwhile also delays the staged code. Replaces the code staged before
+    pub fn stage_code() {
+        assert_upgrade_authority();
+        let code = input_code();
+        let staged_code = StagedCode {
+            hash: env::sha256_array(&code),
+            code,
+            staged_at: oracle::block_timestamp_sec(),
+        };
+        log!(
+            "Code {} is staged, it can be deployed at {}",
+            hex(&staged_code.hash),
+            staged_code.available_at()
+        );
+        staged_code_storage().set(&staged_code);
+    }
+
+    pub fn deploy_staged_code() -> Promise {
+        assert_upgrade_authority();
+        let mut storage = staged_code_storage();
+        let staged_code = storage.get().or_panic(ContractError::StagedCodeNotFound);
+        let available_at = staged_code.available_at();
+        require(
+            oracle::block_timestamp_sec() >= available_at,
+            ContractError::StagedCodeLocked { available_at },
+        );
+        storage.remove();
+        deploy_and_migrate(staged_code.code)
+    }
+
+    pub fn cancel_staged_code() {
+        assert_upgrade_authority();
+        require(
+            staged_code_storage().remove(),
+            ContractError::StagedCodeNotFound,
+        );
+    }
+
+    pub fn get_staged_code() -> Option<StagedCodeView> {
+        staged_code_storage()
+            .get()
+            .map(|staged_code| StagedCodeView {
+                hash: hex(&staged_code.hash),
+                available_at: staged_code.available_at(),
+            })
+    }
+}
@@ -0,0 +1,172 @@
+use crate::*;
+
+// Rest of a zap after its swap leg settles, with the swap output in the deposits of the sender
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+pub enum Zap {
+    // Add amount of token_in that wasn't swapped and the output as liquidity
+    In {
+        amount: Balance,
+        min_shares: Balance,
+    },
+    // amount of token_out was removed from the pool, the output adds to it
+    Out {
+        amount: Balance,
+    },
+}
+
+impl Pool {
+    fn zap_swap_amount(&self, token_in: &AccountId, amount: Balance, fee: u32) -> Balance {
+        let (balance_in, _) = self.balances(token_in);
+        math::zap_swap_amount(
+            amount,
+            balance_in,
+            fee as Balance,
+            fees::FEE_DIVISOR as Balance,
+        )
+        .unwrap_or_panic()
+    }
+}
+
+impl Contract {
+    // Fee in basis points of the swap leg of a zap that doesn't reach the pool: the creator fee,
+    // and the agent fee if the agent prices the swap and takes it from amount_in
+    fn zap_swap_fee(&self, pool: &Pool) -> u32 {
+        match (pool.pricing_mode, self.config.agent_fee_side) {
+            (PricingMode::OnChainOnly, _) | (_, AgentFeeSide::AmountOut) => pool.creator_fee,
+            _ => pool.creator_fee + self.config.agent_fee,
+        }
+    }
+
+    // Swap leg of a zap from the deposits of the sender. It's priced like other swaps of the
+    // pool, by the agent or on-chain, and the rest of the zap runs when it settles
+    fn internal_zap_swap(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount: Balance,
+        min_amount_out: Balance,
+        zap: Zap,
+    ) -> SwapResult {
+        self.internal_request_swap(SwapRequest {
+            sender_id: sender_id.clone(),
+            token_in: token_in.clone(),
+            token_out: token_out.clone(),
+            amount_in: U128(amount),
+            min_amount_out: U128(min_amount_out),
+            receiver_id: None,
+            msg: None,
+            referral_id: None,
+            agent_payment: None,
+            zap: Some(zap),
+        })
+    }
+
+    // Swap the optimal part of amount through the pool, both sides are added as liquidity
+    // when the swap settles
+    pub fn internal_zap_in(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_other: &AccountId,
+        amount: Balance,
+        min_shares: Balance,
+    ) -> SwapResult {
+        let pool = self
+            .pools
+            .get(&get_pool_key(token_in, token_other))
+            .or_panic(ContractError::PoolNotFound);
+
+        let swap_amount = pool.zap_swap_amount(token_in, amount, self.zap_swap_fee(&pool));
+        self.internal_zap_swap(
+            sender_id,
+            token_in,
+            token_other,
+            swap_amount,
+            0,
+            Zap::In {
+                amount: amount - swap_amount,
+                min_shares,
+            },
+        )
+    }
+
+    // Run the rest of the zap after its swap leg settled with amount_out of token_out, returns
+    // the shares minted for a zap in and the total output of a zap out. It never panics, if the
+    // liquidity can't be added the tokens stay in the deposits of the sender
+    pub fn internal_finish_zap(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_out: Balance,
+        zap: Zap,
+    ) -> Balance {
+        match zap {
+            Zap::In { amount, min_shares } => {
+                // the part that wasn't swapped stayed in deposits meanwhile
+                let balance = self
+                    .get_deposits(sender_id)
+                    .tokens
+                    .get(token_in)
+                    .unwrap_or(0);
+                self.internal_try_add_liquidity(
+                    token_in,
+                    token_out,
+                    std::cmp::min(amount, balance),
+                    amount_out,
+                    sender_id,
+                    min_shares,
+                )
+                .unwrap_or_else(|error| {
+                    log!("{}, the zapped tokens stay in deposits", error);
+                    0
+                })
+            }
+            Zap::Out { amount } => amount + amount_out,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Remove liquidity and swap the other side, so the caller receives a single token into
+    // deposits. The swap is priced like other swaps of the pool, the call resolves to the total
+    // amount of token_out, or to null if the swap is refunded and both tokens stay in deposits
+    pub fn zap_out(
+        &mut self,
+        token_a: AccountId,
+        token_b: AccountId,
+        shares: U128,
+        token_out: AccountId,
+        min_amount_out: U128,
+    ) {
+        let sender_id = env::predecessor_account_id();
+        let token_other = if token_out == token_a {
+            token_b
+        } else {
+            require(token_out == token_b, ContractError::TokenNotInPool);
+            token_a
+        };
+
+        let (amount_out, amount_other) =
+            self.internal_remove_liquidity(&token_out, &token_other, shares.0, &sender_id);
+
+        let result = self.internal_zap_swap(
+            &sender_id,
+            &token_other,
+            &token_out,
+            amount_other,
+            min_amount_out.0.saturating_sub(amount_out),
+            Zap::Out { amount: amount_out },
+        );
+        match result {
+            SwapResult::Pending(promise_idx) => env::promise_return(promise_idx),
+            SwapResult::Queued => env::value_return(b"null"),
+            SwapResult::Settled(amount_out) => {
+                env::value_return(serde_json::to_vec(&amount_out).unwrap())
+            }
+        }
+    }
+}